@@ -0,0 +1,56 @@
+// Gives outputs the same permissions, ownership and extended attributes as their source, so
+// that whatever can read the source (e.g. a web server running as another user, or confined
+// by SELinux) can read its compressed copies too.
+
+#[cfg(unix)]
+extern crate xattr;
+
+use std::fs::Metadata;
+use std::path::Path;
+
+// Best effort, like the modification time: ownership can only be given away by root, and
+// not every filesystem supports extended attributes.
+#[cfg(unix)]
+pub fn copy_attributes(src: &Path, src_metadata: &Metadata, dst: &Path, xattrs: &[String]) {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    // Ownership first, as changing it may clear the mode bits
+    if std::os::unix::fs::chown(dst, Some(src_metadata.uid()), Some(src_metadata.gid())).is_err() {
+        // Without root, we may still be able to set a group we belong to
+        std::os::unix::fs::chown(dst, None, Some(src_metadata.gid())).unwrap_or_default();
+    }
+
+    // Never setuid, setgid or sticky
+    let mode = src_metadata.mode() & 0o777;
+    std::fs::set_permissions(dst, std::fs::Permissions::from_mode(mode)).unwrap_or_default();
+
+    if xattrs.is_empty() || !xattr::SUPPORTED_PLATFORM {
+        return;
+    }
+    let names = match xattr::list_deref(src) {
+        Ok(names) => names,
+        Err(_) => return,
+    };
+    for name in names {
+        if !xattrs.iter().any(|pattern| xattr_matches(pattern, &name.to_string_lossy())) {
+            continue;
+        }
+        if let Ok(Some(value)) = xattr::get_deref(src, &name) {
+            xattr::set(dst, &name, &value).unwrap_or_default();
+        }
+    }
+}
+
+// Elsewhere, the only permission is the readonly flag, and copying it would stop the next run
+// from replacing a stale output
+#[cfg(not(unix))]
+pub fn copy_attributes(_src: &Path, _src_metadata: &Metadata, _dst: &Path, _xattrs: &[String]) {}
+
+// Patterns are either a full name or a namespace prefix such as `user.*`
+#[cfg(unix)]
+fn xattr_matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => pattern == name,
+    }
+}
@@ -0,0 +1,112 @@
+use errors::*;
+use separator::Separatable;
+use size::Size;
+use std::io::Read;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use structs::*;
+
+// webp is excluded as it shells out to cwebp and only applies to images
+const BENCH_ALGORITHMS: &[CompressionAlgorithm] = &[
+    CompressionAlgorithm::GZip,
+    CompressionAlgorithm::Zopfli,
+    CompressionAlgorithm::Zstd,
+    CompressionAlgorithm::Brotli,
+];
+
+struct BenchResult {
+    algorithm: CompressionAlgorithm,
+    quality: Option<u8>,
+    input_bytes: u64,
+    output_bytes: u64,
+    elapsed: Duration,
+}
+
+pub struct Benchmark {
+    file_count: usize,
+    results: Vec<BenchResult>,
+}
+
+// Picks up to `count` paths spread evenly across the full list of matches
+pub fn sample(mut paths: Vec<PathBuf>, count: usize) -> Vec<PathBuf> {
+    if paths.len() <= count {
+        return paths;
+    }
+
+    paths.sort();
+    (0..count).map(|i| paths[i * paths.len() / count].clone()).collect()
+}
+
+impl Benchmark {
+    // Compresses each of the sampled files in memory with every algorithm and quality level
+    pub fn run(paths: &[PathBuf], show_progress: bool) -> Result<Benchmark> {
+        let mut files = Vec::with_capacity(paths.len());
+        for path in paths {
+            let mut data = Vec::new();
+            std::fs::File::open(path)?.read_to_end(&mut data)?;
+            files.push(data);
+        }
+
+        let mut results = Vec::new();
+        for algorithm in BENCH_ALGORITHMS {
+            for quality in algorithm.qualities() {
+                if show_progress {
+                    match quality {
+                        Some(q) => println!("{} -q {}", algorithm, q),
+                        None => println!("{}", algorithm),
+                    }
+                }
+
+                let mut result = BenchResult {
+                    algorithm: *algorithm,
+                    quality,
+                    input_bytes: 0,
+                    output_bytes: 0,
+                    elapsed: Duration::from_secs(0),
+                };
+
+                for data in files.iter() {
+                    let start = Instant::now();
+                    let compressed = algorithm.compress_buffer(data, quality)?;
+                    result.elapsed += start.elapsed();
+                    result.input_bytes += data.len() as u64;
+                    result.output_bytes += compressed.len() as u64;
+                }
+
+                results.push(result);
+            }
+        }
+
+        Ok(Benchmark {
+            file_count: files.len(),
+            results,
+        })
+    }
+}
+
+impl std::fmt::Display for Benchmark {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f)?;
+        writeln!(f, "Sampled {} files", self.file_count.separated_string())?;
+        let mut table = table!(["Algorithm", "Quality", "Ratio", "Throughput", "Uncompressed Size", "Compressed Size", "Time"]);
+        for result in self.results.iter() {
+            let seconds = result.elapsed.as_secs_f64();
+            let throughput = match seconds > 0f64 {
+                true => format!("{:.2} MiB/s", result.input_bytes as f64 / seconds / (1024f64 * 1024f64)),
+                false => "-".to_owned(),
+            };
+            table.add_row(row![
+                result.algorithm,
+                result.quality.map(|q| q.to_string()).unwrap_or_else(|| "-".to_owned()),
+                format!("{:.2}%", 100f64 * result.output_bytes as f64 / result.input_bytes as f64),
+                throughput,
+                Size::from_bytes(result.input_bytes),
+                Size::from_bytes(result.output_bytes),
+                format!("{:.3}s", seconds),
+            ]);
+        }
+
+        writeln!(f, "{}", table)?;
+        Ok(())
+    }
+}
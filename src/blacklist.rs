@@ -0,0 +1,199 @@
+// The files that are never compressed: formats that are already compressed, our own outputs,
+// sidecars, manifest and report, and whatever extensions the user has added.
+
+use lists::{COMP_EXTS, IMAGE_EXTS, OUTPUT_EXTS, SIDECAR_EXTS};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use structs::*;
+
+pub struct Blacklist {
+    // Lowercase, without the leading dot
+    exts: HashSet<String>,
+    // Canonical, and so compared only after a cheaper check of the file name
+    paths: Vec<PathBuf>,
+    // The extensions of outputs and sidecars written alongside their sources, possibly
+    // spanning several dots (e.g. `min.gz`)
+    output_exts: Vec<String>,
+}
+
+impl Blacklist {
+    /// The built-in list alone.
+    pub fn builtin() -> Blacklist {
+        let mut exts: HashSet<String> = COMP_EXTS.iter()
+            .flat_map(|&(_, exts)| exts.iter())
+            .map(|ext| ext.to_string())
+            .collect();
+        exts.extend(SIDECAR_EXTS.iter().map(|ext| ext.to_string()));
+        let output_exts = OUTPUT_EXTS.iter().chain(SIDECAR_EXTS.iter()).map(|ext| ext.to_string()).collect();
+        Blacklist { exts, paths: Vec::new(), output_exts }
+    }
+
+    pub fn new(params: &Parameters) -> Blacklist {
+        let mut blacklist = Blacklist::builtin();
+
+        // cwebp takes jpeg and png sources, so only webp images are already "compressed"
+        if params.compressor == CompressionAlgorithm::WebP {
+            for ext in IMAGE_EXTS.iter().filter(|&&ext| ext != "webp") {
+                blacklist.exts.remove(*ext);
+            }
+        }
+
+        blacklist.exts.extend(params.skip_exts.iter().map(|ext| normalize(ext)));
+        for ext in &params.compress_exts {
+            blacklist.exts.remove(&normalize(ext));
+        }
+
+        // Whatever the user asks for, outputs and sidecars are never compressed again (which
+        // would produce e.g. `app.js.gz.br`). Only the last part of an extension such as
+        // `min.gz` is compared.
+        let extension = normalize(&params.extension);
+        let last = extension.rsplit('.').next().unwrap_or_default().to_owned();
+        blacklist.exts.insert(last);
+        blacklist.exts.extend(SIDECAR_EXTS.iter().map(|ext| ext.to_string()));
+        if !blacklist.output_exts.contains(&extension) {
+            blacklist.output_exts.push(extension);
+        }
+        blacklist.paths = params.exclude_paths.iter().filter_map(|p| canonical(p)).collect();
+
+        blacklist
+    }
+
+    pub fn contains(&self, path: &Path) -> bool {
+        let blacklisted = match path.extension() {
+            Some(x) => self.exts.contains(&x.to_string_lossy().to_lowercase()),
+            None => false,
+        };
+
+        blacklisted || self.is_excluded_path(path)
+    }
+
+    /// Whether `path` was written by static-compress, in this run or an earlier one, rather
+    /// than being a source in its own right: an output or sidecar next to its source, or the
+    /// manifest or report.
+    pub fn is_output(&self, path: &Path) -> bool {
+        self.output_exts.iter().any(|ext| source_of(path, ext).map(|src| src.is_file()).unwrap_or(false))
+            || self.is_excluded_path(path)
+    }
+
+    fn is_excluded_path(&self, path: &Path) -> bool {
+        self.paths.iter().any(|p| p.file_name() == path.file_name() && canonical(path).as_ref() == Some(p))
+    }
+}
+
+// The path `path` would be the output of, if it ends with `ext`
+fn source_of(path: &Path, ext: &str) -> Option<PathBuf> {
+    let mut source = path.to_path_buf();
+    for part in ext.rsplit('.') {
+        match source.extension() {
+            Some(x) if x.to_string_lossy().eq_ignore_ascii_case(part) => source.set_extension(""),
+            _ => return None,
+        };
+    }
+    Some(source)
+}
+
+// Also resolves paths that don't exist yet, such as a manifest about to be created
+fn canonical(path: &Path) -> Option<PathBuf> {
+    if let Ok(path) = std::fs::canonicalize(path) {
+        return Some(path);
+    }
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let name = path.file_name()?;
+    std::fs::canonicalize(parent).ok().map(|p| p.join(name))
+}
+
+fn normalize(ext: &str) -> String {
+    ext.trim().trim_start_matches('.').to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn compressed_formats_are_skipped() {
+        let blacklist = Blacklist::builtin();
+        for name in &["a.png", "a.JPG", "a.woff2", "a.zip", "a.tar.gz", "a.js.br", "a.js.sri", "a.js.gz.etag"] {
+            assert!(blacklist.contains(Path::new(name)), "{}", name);
+        }
+        for name in &["a.js", "a.svg", "a.tar", "Makefile"] {
+            assert!(!blacklist.contains(Path::new(name)), "{}", name);
+        }
+    }
+
+    #[test]
+    fn user_extensions_take_precedence() {
+        let mut params = Parameters::new(CompressionAlgorithm::Brotli);
+        params.skip_exts = vec![".Map".to_owned(), "txt".to_owned()];
+        params.compress_exts = vec!["WOFF".to_owned(), "br".to_owned(), "etag".to_owned()];
+        let blacklist = Blacklist::new(&params);
+
+        assert!(blacklist.contains(Path::new("app.js.map")));
+        assert!(blacklist.contains(Path::new("README.TXT")));
+        assert!(!blacklist.contains(Path::new("font.woff")));
+        assert!(blacklist.contains(Path::new("font.woff2")));
+        // Our own outputs and sidecars stay skipped
+        assert!(blacklist.contains(Path::new("app.js.br")));
+        assert!(blacklist.contains(Path::new("app.js.br.etag")));
+        // Outputs of other algorithms can be asked for
+        params.compress_exts = vec!["gz".to_owned()];
+        assert!(!Blacklist::new(&params).contains(Path::new("app.js.gz")));
+    }
+
+    #[test]
+    fn webp_compresses_other_images() {
+        let blacklist = Blacklist::new(&Parameters::new(CompressionAlgorithm::WebP));
+        for name in &["a.png", "a.jpg", "a.jpeg"] {
+            assert!(!blacklist.contains(Path::new(name)), "{}", name);
+        }
+        assert!(blacklist.contains(Path::new("a.webp")));
+        assert!(blacklist.contains(Path::new("a.mp4")));
+    }
+
+    #[test]
+    fn excluded_paths_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        fs::create_dir(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/report.json"), b"").unwrap();
+        let mut params = Parameters::new(CompressionAlgorithm::GZip);
+        // Not written yet, and reached by another path
+        params.exclude_paths = vec![dir.join("manifest.json"), dir.join("sub/../sub/report.json")];
+        let blacklist = Blacklist::new(&params);
+
+        fs::write(dir.join("manifest.json"), b"").unwrap();
+        assert!(blacklist.contains(&dir.join("manifest.json")));
+        assert!(blacklist.contains(&dir.join("sub/report.json")));
+        assert!(!blacklist.contains(&dir.join("report.json")));
+    }
+
+    #[test]
+    fn outputs_are_recognised_by_their_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        for name in &["app.js", "app.js.gz", "app.js.br", "app.js.sri", "app.js.gz.etag", "app.js.min.gz",
+                      "site.css.GZ", "lone.js.gz", "archive.tar.gz", "report.json"] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+        let mut params = Parameters::new(CompressionAlgorithm::GZip);
+        params.extension = "min.gz".to_owned();
+        params.exclude_paths = vec![dir.join("report.json")];
+        let blacklist = Blacklist::new(&params);
+
+        for name in &["app.js.gz", "app.js.br", "app.js.sri", "app.js.gz.etag", "app.js.min.gz", "report.json"] {
+            assert!(blacklist.is_output(&dir.join(name)), "{}", name);
+        }
+        // Without its source alongside, a compressed file is a source like any other
+        for name in &["app.js", "site.css.GZ", "lone.js.gz", "archive.tar.gz"] {
+            assert!(!blacklist.is_output(&dir.join(name)), "{}", name);
+        }
+        fs::write(dir.join("site.css"), b"").unwrap();
+        assert!(blacklist.is_output(&dir.join("site.css.GZ")));
+    }
+}
@@ -1,96 +1,228 @@
 extern crate brotli2;
 extern crate flate2;
 extern crate zopfli;
+extern crate zstd;
 
+use digest::*;
+use lists::TEMP_SUFFIX;
 use structs::*;
 use errors::*;
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{BufWriter, Read, Write};
 
 use std::path::Path;
 
 impl CompressionFormat for CompressionAlgorithm {
     fn extension(&self) -> &'static str {
         match self {
-            &CompressionAlgorithm::Brotli => "br",
-            &CompressionAlgorithm::GZip => "gz",
-            &CompressionAlgorithm::WebP => "webp",
-            &CompressionAlgorithm::Zopfli => "gz",
+            CompressionAlgorithm::Brotli => "br",
+            CompressionAlgorithm::GZip => "gz",
+            CompressionAlgorithm::WebP => "webp",
+            CompressionAlgorithm::Zopfli => "gz",
+            CompressionAlgorithm::Zstd => "zst",
+        }
+    }
+
+    fn content_encoding(&self) -> Option<&'static str> {
+        match self {
+            CompressionAlgorithm::Brotli => Some("br"),
+            CompressionAlgorithm::GZip => Some("gzip"),
+            CompressionAlgorithm::WebP => None,
+            CompressionAlgorithm::Zopfli => Some("gzip"),
+            CompressionAlgorithm::Zstd => Some("zstd"),
         }
     }
 }
 
 impl FileCompressor for CompressionAlgorithm {
-    fn compress(&self, src: &Path, dst: &Path, quality: Option<u8>) -> Result<()> {
+    fn compress_stream(&self, src: &mut dyn Read, dst: &mut dyn Write, quality: Option<u8>) -> Result<()> {
         match self {
-            &CompressionAlgorithm::GZip => gzip_compress(src, dst, quality),
-            &CompressionAlgorithm::Brotli => brotli_compress(src, dst, quality),
-            &CompressionAlgorithm::WebP => webp_compress(src, dst, quality),
-            &CompressionAlgorithm::Zopfli => zopfli_compress(src, dst, quality),
+            CompressionAlgorithm::GZip => gzip_encode(src, dst, quality),
+            CompressionAlgorithm::Brotli => brotli_encode(src, dst, quality, brotli2::CompressMode::Generic),
+            CompressionAlgorithm::WebP => bail!("webp compression requires an on-disk source!"),
+            CompressionAlgorithm::Zopfli => zopfli_encode(src, dst, quality),
+            CompressionAlgorithm::Zstd => zstd_encode(src, dst, quality, 0),
             // _ => bail!("Compression algorithm not implemented!"),
         }
     }
+
+    fn compress(&self, src: &Path, dst: &Path, quality: Option<u8>) -> Result<()> {
+        match self {
+            CompressionAlgorithm::WebP => webp_compress(src, dst, quality),
+            _ => compress_file(self, src, dst, quality),
+        }
+    }
+}
+
+impl FileCompressor for Candidate {
+    fn compress_stream(&self, src: &mut dyn Read, dst: &mut dyn Write, quality: Option<u8>) -> Result<()> {
+        (self.encoder)(src, dst, self.quality.or(quality))
+    }
 }
 
-fn gzip_compress(src_path: &Path, dst_path: &Path, quality: Option<u8>) -> Result<()> {
+// The default path-based compression, layered over FileCompressor::compress_stream()
+pub fn compress_file<C: FileCompressor + ?Sized>(compressor: &C, src_path: &Path, dst_path: &Path, quality: Option<u8>) -> Result<()> {
     let mut src = File::open(src_path)?;
-    let dst = File::create(dst_path)?;
+    let mut dst = BufWriter::new(File::create(dst_path)?);
 
-    let level = match quality {
-        None => flate2::Compression::default(),
-        Some(0) => flate2::Compression::none(),
-        Some(1) => flate2::Compression::fast(),
-        Some(2..=6) => flate2::Compression::default(),
-        Some(3..=9) => flate2::Compression::best(),
-        _ => bail!("Invalid --quality parameter specified!"),
-    };
+    compressor.compress_stream(&mut src, &mut dst, quality)?;
+    dst.flush().chain_err(|| "Error writing to destination file!")?;
+    Ok(())
+}
 
-    let mut encoder = flate2::write::GzEncoder::new(dst, level);
+// Like compress_file(), but digests the source and the output as they are streamed
+pub fn compress_file_digested<C: FileCompressor + ?Sized>(compressor: &C, src_path: &Path, dst_path: &Path, quality: Option<u8>,
+                                                         integrity: &[SriAlgorithm]) -> Result<Digests> {
+    let mut src = HashingReader::new(File::open(src_path)?, integrity);
+    let mut dst = HashingWriter::new(BufWriter::new(File::create(dst_path)?));
+
+    compressor.compress_stream(&mut src, &mut dst, quality)?;
+    dst.flush().chain_err(|| "Error writing to destination file!")?;
+    Ok(Digests {
+        integrity: src.integrity(),
+        sha256: dst.sha256(),
+    })
+}
+
+impl BestOf {
+    pub fn for_format(format: &dyn CompressionFormat) -> Result<BestOf> {
+        let candidates = match format.extension() {
+            "gz" => vec![
+                Candidate { name: "gzip-9", quality: Some(9), encoder: gzip_encode },
+                Candidate { name: "zopfli", quality: None, encoder: |src, dst, _| zopfli_encode(src, dst, None) },
+            ],
+            "br" => vec![
+                Candidate { name: "brotli-generic", quality: None, encoder: |src, dst, quality| {
+                    brotli_encode(src, dst, quality, brotli2::CompressMode::Generic)
+                }},
+                Candidate { name: "brotli-text", quality: None, encoder: |src, dst, quality| {
+                    brotli_encode(src, dst, quality, brotli2::CompressMode::Text)
+                }},
+            ],
+            ext => bail!("Best-of mode is not supported for .{} output", ext),
+        };
+
+        Ok(BestOf { candidates })
+    }
+
+    // Runs every candidate against the source and keeps the smallest output as `dst`,
+    // returning the name of the winning candidate.
+    pub fn compress(&self, src: &Path, dst: &Path, quality: Option<u8>) -> Result<&'static str> {
+        let mut temp_paths = Vec::with_capacity(self.candidates.len());
+        let result = (|| -> Result<&'static str> {
+            let mut best: Option<(usize, u64)> = None;
+            for (i, candidate) in self.candidates.iter().enumerate() {
+                // Hidden, so a concurrent directory walk won't pick it up
+                let mut temp_name = std::ffi::OsString::from(".");
+                temp_name.push(dst.file_name().ok_or(ErrorKind::InvalidCharactersInPath)?);
+                temp_name.push(format!(".{}{}", candidate.name, TEMP_SUFFIX));
+                temp_paths.push(dst.with_file_name(temp_name));
+                let temp_path = &temp_paths[i];
+
+                candidate.compress(src, temp_path, quality)?;
+                let size = std::fs::metadata(temp_path)?.len();
+                match best {
+                    Some((_, best_size)) if best_size <= size => {},
+                    _ => best = Some((i, size)),
+                }
+            }
+
+            let (winner, _) = best.ok_or("No best-of candidates configured!")?;
+            std::fs::rename(&temp_paths[winner], dst)?;
+            Ok(self.candidates[winner].name)
+        })();
+
+        // Clean up the losing outputs (and everything else on error)
+        for temp_path in temp_paths.iter() {
+            std::fs::remove_file(temp_path).unwrap_or_default();
+        }
+
+        result
+    }
+}
+
+impl CompressionAlgorithm {
+    // The --quality values accepted by this algorithm, or `None` if it takes none
+    pub fn qualities(&self) -> Vec<Option<u8>> {
+        match self {
+            CompressionAlgorithm::GZip => (0..=9).map(Some).collect(),
+            CompressionAlgorithm::Brotli => (0..=11).map(Some).collect(),
+            CompressionAlgorithm::WebP => (0..=100).map(Some).collect(),
+            CompressionAlgorithm::Zopfli => vec![None],
+            CompressionAlgorithm::Zstd => (1..=22).map(Some).collect(),
+        }
+    }
+}
+
+fn encode_all(src: &mut dyn Read, encoder: &mut dyn Write) -> Result<()> {
     let mut buf = [0u8; 1024];
     loop {
         let bytes_read = src.read(&mut buf).chain_err(|| "Error reading from source file!")?;
         match bytes_read {
             0 => break, // End-of-file
-            l => encoder.write_all(&buf[0..l]).chain_err(|| "Fatal gzip encoder error!")?,
+            l => encoder.write_all(&buf[0..l]).chain_err(|| "Fatal encoder error!")?,
         };
     }
 
     Ok(())
 }
 
-fn brotli_compress(src_path: &Path, dst_path: &Path, quality: Option<u8>) -> Result<()> {
-    let mut src = File::open(src_path)?;
-    let dst = File::create(dst_path)?;
+pub fn gzip_level(quality: Option<u8>) -> Result<flate2::Compression> {
+    let level = match quality {
+        None => flate2::Compression::default(),
+        Some(q @ 0..=9) => flate2::Compression::new(q as u32),
+        _ => bail!("Invalid --quality parameter specified!"),
+    };
+
+    Ok(level)
+}
 
+fn gzip_encode(src: &mut dyn Read, dst: &mut dyn Write, quality: Option<u8>) -> Result<()> {
+    let level = gzip_level(quality)?;
+    let mut encoder = flate2::write::GzEncoder::new(dst, level);
+    encode_all(src, &mut encoder)?;
+    encoder.finish().chain_err(|| "Fatal gzip encoder error!")?;
+    Ok(())
+}
+
+fn brotli_encode(src: &mut dyn Read, dst: &mut dyn Write, quality: Option<u8>, mode: brotli2::CompressMode) -> Result<()> {
     let level = match quality {
         None => 6,
         Some(q @ 0..=11) => q,
         _ => bail!("Invalid --quality parameter specified!"),
     };
 
-    let mut encoder = brotli2::write::BrotliEncoder::new(dst, level as u32);
-    let mut buf = [0u8; 1024];
-    loop {
-        let bytes_read = src.read(&mut buf).chain_err(|| "Error reading from source file!")?;
-        match bytes_read {
-            0 => break, // End-of-file
-            l => encoder.write_all(&buf[0..l]).chain_err(|| "Fatal gzip encoder error!")?,
-        };
-    }
-
+    let mut params = brotli2::CompressParams::new();
+    params.quality(level as u32).mode(mode);
+    let mut encoder = brotli2::write::BrotliEncoder::from_params(dst, &params);
+    encode_all(src, &mut encoder)?;
+    encoder.finish().chain_err(|| "Fatal brotli encoder error!")?;
     Ok(())
 }
 
-fn zopfli_compress(src_path: &Path, dst_path: &Path, quality: Option<u8>) -> Result<()> {
+fn zopfli_encode(src: &mut dyn Read, dst: &mut dyn Write, quality: Option<u8>) -> Result<()> {
     if quality.is_some() {
         bail!("--quality is not implemented for zopfli compression");
     }
 
-    let src = File::open(src_path)?;
-    let dst = File::create(dst_path)?;
-
     zopfli::compress(&zopfli::Options::default(), &zopfli::Format::Gzip, src, dst)?;
+    Ok(())
+}
 
+// `workers` background threads, or none to compress on the calling thread
+pub fn zstd_encode(src: &mut dyn Read, dst: &mut dyn Write, quality: Option<u8>, workers: u32) -> Result<()> {
+    let level = match quality {
+        None => zstd::DEFAULT_COMPRESSION_LEVEL,
+        Some(q @ 1..=22) => q as i32,
+        _ => bail!("Invalid --quality parameter specified!"),
+    };
+
+    let mut encoder = zstd::stream::write::Encoder::new(dst, level).chain_err(|| "Fatal zstd encoder error!")?;
+    if workers > 0 {
+        encoder.multithread(workers).chain_err(|| "Fatal zstd encoder error!")?;
+    }
+    encode_all(src, &mut encoder)?;
+    encoder.finish().chain_err(|| "Fatal zstd encoder error!")?;
     Ok(())
 }
 
@@ -112,3 +244,78 @@ fn webp_compress(src_path: &Path, dst_path: &Path, quality: Option<u8>) -> Resul
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use super::*;
+    use self::flate2::read::GzDecoder;
+
+    // Without a quality of its own
+    fn candidate(name: &'static str, encoder: Encoder) -> Candidate {
+        Candidate { name, quality: None, encoder }
+    }
+
+    fn files(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(dir).unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn smallest_candidate_wins() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("a.txt");
+        let dst = dir.path().join("a.txt.gz");
+        std::fs::write(&src, b"source").unwrap();
+
+        let best_of = BestOf {
+            candidates: vec![
+                candidate("long", |_, dst, _| Ok(dst.write_all(b"longest")?)),
+                candidate("short", |_, dst, _| Ok(dst.write_all(b"short")?)),
+                // Ties go to the earlier candidate
+                candidate("tied", |_, dst, _| Ok(dst.write_all(b"tied!")?)),
+            ],
+        };
+        assert_eq!(best_of.compress(&src, &dst, None).unwrap(), "short");
+        assert_eq!(std::fs::read(&dst).unwrap(), b"short");
+        assert_eq!(files(dir.path()), vec!["a.txt", "a.txt.gz"]);
+    }
+
+    #[test]
+    fn failed_candidates_leave_nothing_behind() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("a.txt");
+        std::fs::write(&src, b"source").unwrap();
+
+        let best_of = BestOf {
+            candidates: vec![
+                candidate("ok", |_, dst, _| Ok(dst.write_all(b"ok")?)),
+                candidate("broken", |_, _, _| bail!("boom")),
+            ],
+        };
+        assert!(best_of.compress(&src, &dir.path().join("a.txt.gz"), None).is_err());
+        assert_eq!(files(dir.path()), vec!["a.txt"]);
+    }
+
+    #[test]
+    fn gzip_candidates_produce_gzip() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("a.html");
+        let dst = dir.path().join("a.html.gz");
+        let data = "<p>hello</p>\n".repeat(100);
+        std::fs::write(&src, &data).unwrap();
+
+        let best_of = BestOf::for_format(&CompressionAlgorithm::Zopfli).unwrap();
+        let winner = best_of.compress(&src, &dst, None).unwrap();
+        assert!(["gzip-9", "zopfli"].contains(&winner));
+        let mut decoded = String::new();
+        GzDecoder::new(File::open(&dst).unwrap()).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, data);
+
+        assert!(BestOf::for_format(&CompressionAlgorithm::Zstd).is_err());
+    }
+}
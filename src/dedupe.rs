@@ -0,0 +1,244 @@
+// Shares compressed output between sources with identical contents, so each is compressed once
+
+#[cfg(unix)]
+extern crate xattr;
+
+use digest::{self, Digests};
+use errors::*;
+use filetime::FileTime;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+// The output of the first source with a given content hash
+#[derive(Clone)]
+pub struct Original {
+    pub output: PathBuf,
+    // Of the source
+    pub modified: FileTime,
+    pub output_size: u64,
+    pub duration: Duration,
+    pub algorithm: &'static str,
+    pub digests: Option<Digests>,
+    // The output is tagged with the source's content hash, so it can be hardlinked whatever
+    // the mtimes of the sources sharing it
+    pub tagged: bool,
+}
+
+enum State {
+    Pending,
+    Done(Original),
+    Failed,
+}
+
+type Slot = Arc<(Mutex<State>, Condvar)>;
+
+pub enum Claim {
+    // The first source with this content, which must be compressed and then completed
+    Lead(Lead),
+    // An identical source has already been compressed, or `None` if that failed
+    Duplicate(Option<Original>),
+}
+
+#[derive(Default)]
+pub struct Deduplicator {
+    seen: Mutex<HashMap<String, Slot>>,
+}
+
+impl Deduplicator {
+    // Blocks while another worker is still compressing identical content
+    pub fn claim(&self, content_hash: String) -> Claim {
+        let slot = {
+            let mut seen = self.seen.lock().unwrap();
+            match seen.get(&content_hash) {
+                Some(slot) => slot.clone(),
+                None => {
+                    let slot = Arc::new((Mutex::new(State::Pending), Condvar::new()));
+                    seen.insert(content_hash.clone(), slot.clone());
+                    return Claim::Lead(Lead { slot, content_hash });
+                }
+            }
+        };
+
+        let (ref state, ref cvar) = *slot;
+        let mut state = state.lock().unwrap();
+        while let State::Pending = *state {
+            state = cvar.wait(state).unwrap();
+        }
+
+        match *state {
+            State::Done(ref original) => Claim::Duplicate(Some(original.clone())),
+            _ => Claim::Duplicate(None),
+        }
+    }
+}
+
+pub struct Lead {
+    slot: Slot,
+    pub content_hash: String,
+}
+
+impl Lead {
+    pub fn complete(self, original: Original) {
+        self.finish(State::Done(original));
+    }
+
+    fn finish(&self, result: State) {
+        let (ref state, ref cvar) = *self.slot;
+        let mut state = state.lock().unwrap();
+        if let State::Pending = *state {
+            *state = result;
+            cvar.notify_all();
+        }
+    }
+}
+
+impl Drop for Lead {
+    // Releases any waiting duplicates if compression failed
+    fn drop(&mut self) {
+        self.finish(State::Failed);
+    }
+}
+
+// Linked outputs share one mtime, which is used to detect stale outputs, so hardlinks are only
+// used when the sources' mtimes match or the output is tagged with their content hash. Returns
+// whether a hardlink was created.
+pub fn link_output(original: &Original, dst: &Path, src_modified: FileTime) -> std::io::Result<bool> {
+    let linkable = original.modified == src_modified || original.tagged;
+    if linkable && std::fs::hard_link(&original.output, dst).is_ok() {
+        return Ok(true);
+    }
+
+    // Reflinks where the filesystem supports it (via copy_file_range on Linux)
+    std::fs::copy(&original.output, dst)?;
+    Ok(false)
+}
+
+// Stored on the output's inode, and so shared by every hardlink to it
+#[cfg(unix)]
+const CONTENT_HASH_XATTR: &str = "user.static-compress.sha256";
+
+// Records the hash of the content `output` was compressed from, returning whether the
+// filesystem supports doing so
+#[cfg(unix)]
+pub fn tag_output(output: &Path, content_hash: &str) -> bool {
+    xattr::SUPPORTED_PLATFORM && xattr::set(output, CONTENT_HASH_XATTR, content_hash.as_bytes()).is_ok()
+}
+
+#[cfg(not(unix))]
+pub fn tag_output(_output: &Path, _content_hash: &str) -> bool {
+    false
+}
+
+// Whether `dst` is a hardlinked output, left by an earlier run, that was compressed from the
+// same content as `src`. Its mtime is that of whichever source was compressed first.
+#[cfg(unix)]
+pub fn shares_content(src: &Path, dst: &Path, dst_metadata: &std::fs::Metadata) -> Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    if dst_metadata.nlink() < 2 || !xattr::SUPPORTED_PLATFORM {
+        return Ok(false);
+    }
+    match xattr::get(dst, CONTENT_HASH_XATTR) {
+        Ok(Some(tag)) => Ok(tag == digest::sha256_file(src)?.as_bytes()),
+        _ => Ok(false),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn shares_content(_src: &Path, _dst: &Path, _dst_metadata: &std::fs::Metadata) -> Result<bool> {
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+
+    fn original(output: PathBuf, modified: FileTime, tagged: bool) -> Original {
+        Original {
+            output,
+            modified,
+            output_size: 0,
+            duration: Duration::from_secs(0),
+            algorithm: "gzip",
+            digests: None,
+            tagged,
+        }
+    }
+
+    #[test]
+    fn duplicates_wait_for_the_lead() {
+        let dedupe = Deduplicator::default();
+        let lead = match dedupe.claim("a".to_owned()) {
+            Claim::Lead(lead) => lead,
+            Claim::Duplicate(_) => panic!("The first claim leads"),
+        };
+
+        std::thread::scope(|scope| {
+            let duplicate = scope.spawn(|| dedupe.claim("a".to_owned()));
+            lead.complete(original(PathBuf::from("a.gz"), FileTime::zero(), false));
+            match duplicate.join().unwrap() {
+                Claim::Duplicate(Some(original)) => assert_eq!(original.output, PathBuf::from("a.gz")),
+                _ => panic!("Expected the lead's output"),
+            }
+        });
+
+        // A lead dropped without completing has failed
+        match dedupe.claim("b".to_owned()) {
+            Claim::Lead(lead) => drop(lead),
+            Claim::Duplicate(_) => panic!("The first claim leads"),
+        }
+        assert!(matches!(dedupe.claim("b".to_owned()), Claim::Duplicate(None)));
+    }
+
+    #[test]
+    fn links_only_when_mtimes_match_or_tagged() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("a.gz");
+        fs::write(&output, b"compressed").unwrap();
+        let mtime = FileTime::from_unix_time(1_000_000, 0);
+        let other = FileTime::from_unix_time(2_000_000, 0);
+
+        let linked = |name: &str, original: &Original, modified: FileTime| {
+            let dst = dir.path().join(name);
+            let hardlinked = link_output(original, &dst, modified).unwrap();
+            assert_eq!(fs::read(&dst).unwrap(), b"compressed");
+            hardlinked
+        };
+        assert!(linked("b.gz", &original(output.clone(), mtime, false), mtime));
+        assert!(!linked("c.gz", &original(output.clone(), mtime, false), other));
+        assert!(linked("d.gz", &original(output.clone(), mtime, true), other));
+        assert_eq!(fs::metadata(&output).unwrap().nlink(), 3);
+    }
+
+    #[test]
+    fn tagged_links_share_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("a.js");
+        let copy = dir.path().join("b.js");
+        let output = dir.path().join("a.js.gz");
+        let link = dir.path().join("b.js.gz");
+        fs::write(&src, b"alert(1)").unwrap();
+        fs::write(&copy, b"alert(1)").unwrap();
+        fs::write(&output, b"compressed").unwrap();
+
+        let hash = digest::sha256_file(&src).unwrap();
+        if !tag_output(&output, &hash) {
+            // Extended attributes aren't supported here
+            return;
+        }
+        // Not yet hardlinked
+        assert!(!shares_content(&copy, &output, &fs::metadata(&output).unwrap()).unwrap());
+
+        fs::hard_link(&output, &link).unwrap();
+        let metadata = fs::metadata(&link).unwrap();
+        assert!(shares_content(&copy, &link, &metadata).unwrap());
+        fs::write(&copy, b"alert(2)").unwrap();
+        assert!(!shares_content(&copy, &link, &metadata).unwrap());
+    }
+}
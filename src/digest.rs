@@ -0,0 +1,183 @@
+// Content digests for Subresource Integrity, ETags and the manifest, computed while streaming
+
+extern crate base64;
+extern crate sha2;
+
+use self::base64::Engine;
+use self::sha2::{Digest, Sha256, Sha384, Sha512};
+use errors::*;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SriAlgorithm {
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl SriAlgorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SriAlgorithm::Sha256 => "sha256",
+            SriAlgorithm::Sha384 => "sha384",
+            SriAlgorithm::Sha512 => "sha512",
+        }
+    }
+}
+
+impl std::str::FromStr for SriAlgorithm {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let r = match s {
+            "sha256" => SriAlgorithm::Sha256,
+            "sha384" => SriAlgorithm::Sha384,
+            "sha512" => SriAlgorithm::Sha512,
+            _ => bail!("Unsupported SRI algorithm!"),
+        };
+
+        Ok(r)
+    }
+}
+
+enum Hasher {
+    Sha256(Sha256),
+    Sha384(Sha384),
+    Sha512(Sha512),
+}
+
+impl Hasher {
+    fn new(algorithm: SriAlgorithm) -> Hasher {
+        match algorithm {
+            SriAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            SriAlgorithm::Sha384 => Hasher::Sha384(Sha384::new()),
+            SriAlgorithm::Sha512 => Hasher::Sha512(Sha512::new()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Sha384(h) => h.update(data),
+            Hasher::Sha512(h) => h.update(data),
+        }
+    }
+
+    fn finalize(self) -> Vec<u8> {
+        match self {
+            Hasher::Sha256(h) => h.finalize().to_vec(),
+            Hasher::Sha384(h) => h.finalize().to_vec(),
+            Hasher::Sha512(h) => h.finalize().to_vec(),
+        }
+    }
+}
+
+/// Computes SRI digests of everything read through it.
+pub struct HashingReader<R> {
+    inner: R,
+    hashers: Vec<(SriAlgorithm, Hasher)>,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R, algorithms: &[SriAlgorithm]) -> HashingReader<R> {
+        HashingReader {
+            inner,
+            hashers: algorithms.iter().map(|&a| (a, Hasher::new(a))).collect(),
+        }
+    }
+
+    // The value of an `integrity` attribute, or `None` if no algorithms were requested
+    pub fn integrity(self) -> Option<String> {
+        if self.hashers.is_empty() {
+            return None;
+        }
+
+        let digests: Vec<String> = self.hashers.into_iter()
+            .map(|(algorithm, hasher)| {
+                format!("{}-{}", algorithm.name(), base64::engine::general_purpose::STANDARD.encode(hasher.finalize()))
+            })
+            .collect();
+        Some(digests.join(" "))
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        for &mut (_, ref mut hasher) in self.hashers.iter_mut() {
+            hasher.update(&buf[..read]);
+        }
+        Ok(read)
+    }
+}
+
+/// Computes the SHA-256 of everything written through it.
+pub struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W) -> HashingWriter<W> {
+        HashingWriter {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    // Hex-encoded
+    pub fn sha256(self) -> String {
+        format!("{:x}", self.hasher.finalize())
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The digests of a source file and its compressed output.
+#[derive(Clone)]
+pub struct Digests {
+    pub integrity: Option<String>,
+    pub sha256: String,
+}
+
+impl Digests {
+    // For outputs that weren't streamed through static-compress, e.g. webp or best-of
+    pub fn of_files(src: &Path, dst: &Path, integrity: &[SriAlgorithm]) -> Result<Digests> {
+        Ok(Digests {
+            integrity: integrity_file(src, integrity)?,
+            sha256: sha256_file(dst)?,
+        })
+    }
+}
+
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut hasher = HashingWriter::new(std::io::sink());
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher.sha256())
+}
+
+pub fn integrity_file(path: &Path, algorithms: &[SriAlgorithm]) -> Result<Option<String>> {
+    if algorithms.is_empty() {
+        return Ok(None);
+    }
+
+    let mut reader = HashingReader::new(File::open(path)?, algorithms);
+    std::io::copy(&mut reader, &mut std::io::sink())?;
+    Ok(reader.integrity())
+}
+
+// A strong ETag derived from a hex SHA-256, shared by the sidecars, embed::etag() and serve
+pub fn etag_from_sha256(sha256: &str) -> String {
+    format!("\"{}\"", &sha256[..32.min(sha256.len())])
+}
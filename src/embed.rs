@@ -0,0 +1,286 @@
+//! Helpers for `build.rs` scripts that precompress a directory of web assets and embed them,
+//! along with their encoded variants, into the compiled binary.
+//!
+//! ```ignore
+//! // build.rs
+//! static_compress::embed::Assets::new("web/dist", &["**/*"]).generate().unwrap();
+//!
+//! // src/main.rs
+//! include!(concat!(env!("OUT_DIR"), "/assets.rs"));
+//! ```
+//!
+//! The generated file defines `Asset` and `Encoding` structs, a sorted `ASSETS` table and a
+//! `get(path)` lookup function, where `path` is relative to the asset directory (e.g.
+//! `css/site.css`). Each encoding carries its own ETag, as caches must be able to tell the
+//! representations apart.
+
+extern crate sha2;
+
+use self::sha2::{Digest, Sha256};
+use blacklist::Blacklist;
+use digest;
+use errors::*;
+use lists::*;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use structs::*;
+
+pub struct Assets {
+    pub root: PathBuf,
+    pub filters: Vec<String>,
+    // Each encoded variant to generate, along with its quality parameter
+    pub encodings: Vec<(CompressionAlgorithm, Option<u8>)>,
+    // The name of the generated source file within OUT_DIR
+    pub file_name: String,
+}
+
+struct EmbeddedFile {
+    key: String,
+    source: PathBuf,
+    content_type: &'static str,
+    etag: String,
+    variants: Vec<EmbeddedVariant>,
+}
+
+struct EmbeddedVariant {
+    content_encoding: &'static str,
+    path: PathBuf,
+    etag: String,
+}
+
+impl Assets {
+    /// Embeds the files under `root` matching any of `filters` (relative to `root`) with
+    /// gzip and brotli variants at their highest quality levels.
+    pub fn new<P: Into<PathBuf>>(root: P, filters: &[&str]) -> Assets {
+        Assets {
+            root: root.into(),
+            filters: filters.iter().map(|f| f.to_string()).collect(),
+            encodings: vec![
+                (CompressionAlgorithm::GZip, Some(9)),
+                (CompressionAlgorithm::Brotli, Some(11)),
+            ],
+            file_name: "assets.rs".to_owned(),
+        }
+    }
+
+    /// Compresses the matching assets into `OUT_DIR` and writes the generated lookup table,
+    /// returning its path.
+    pub fn generate(&self) -> Result<PathBuf> {
+        let out_dir = PathBuf::from(std::env::var_os("OUT_DIR")
+                                    .ok_or("OUT_DIR is not set; generate() must be called from a build script")?);
+        let files = self.compress_into(&out_dir.join("static-compress"))?;
+
+        let generated = out_dir.join(&self.file_name);
+        File::create(&generated)?.write_all(generate_source(&files)?.as_bytes())?;
+
+        // Regenerate whenever anything in the asset directory changes
+        println!("cargo:rerun-if-changed={}", self.root.display());
+
+        Ok(generated)
+    }
+
+    // Writes the encoded variants of each matching asset beneath `variant_dir`
+    fn compress_into(&self, variant_dir: &Path) -> Result<Vec<EmbeddedFile>> {
+        for (algorithm, _) in self.encodings.iter() {
+            if algorithm.content_encoding().is_none() {
+                bail!("{} is not an HTTP content-encoding and cannot be embedded", algorithm);
+            }
+        }
+
+        // Search relative to the current directory like the command-line utility does
+        let root = match self.root.is_relative() {
+            true => Path::new(".").join(&self.root),
+            false => self.root.clone(),
+        };
+        let root_str = root.to_str().ok_or(ErrorKind::InvalidCharactersInPath)?;
+        let filters: Vec<String> = self.filters.iter()
+            .map(|f| format!("{}/{}", escape_glob(root_str.trim_end_matches('/')), f))
+            .collect();
+
+        // Already-compressed formats are embedded as they are
+        let blacklist = Blacklist::builtin();
+        let mut files = Vec::new();
+        for path in ::find_files(&filters, true)? {
+            let relative = path.strip_prefix(&root).chain_err(|| "Matched asset outside of the asset directory!")?;
+            let key = relative.components()
+                .map(|c| c.as_os_str().to_str())
+                .collect::<Option<Vec<_>>>()
+                .ok_or(ErrorKind::InvalidCharactersInPath)?
+                .join("/");
+
+            let mut data = Vec::new();
+            File::open(&path)?.read_to_end(&mut data)?;
+
+            let mut variants = Vec::new();
+            for &(algorithm, quality) in self.encodings.iter().filter(|_| !blacklist.contains(&path)) {
+                let encoded = algorithm.compress_buffer(&data, quality)
+                    .chain_err(|| format!("Error compressing {}", path.display()))?;
+                if encoded.len() >= data.len() {
+                    // Not worth serving
+                    continue;
+                }
+
+                let mut variant_path = variant_dir.join(relative).into_os_string();
+                variant_path.push(".");
+                variant_path.push(algorithm.extension());
+                let variant_path = PathBuf::from(variant_path);
+                if let Some(parent) = variant_path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                File::create(&variant_path)?.write_all(&encoded)?;
+                variants.push(EmbeddedVariant {
+                    content_encoding: algorithm.content_encoding().expect("Checked above"),
+                    path: variant_path,
+                    etag: etag(&encoded),
+                });
+            }
+
+            files.push(EmbeddedFile {
+                content_type: content_type(&path),
+                etag: etag(&data),
+                source: std::fs::canonicalize(&path)?,
+                key,
+                variants,
+            });
+        }
+        files.sort_by(|a, b| a.key.cmp(&b.key));
+
+        Ok(files)
+    }
+}
+
+// Looks up the MIME type for a path by its extension
+pub fn content_type(path: &Path) -> &'static str {
+    let ext = path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase());
+
+    match ext {
+        Some(ext) => match MIME_TYPES.binary_search_by(|&(probe, _)| probe.cmp(ext.as_str())) {
+            Ok(i) => MIME_TYPES[i].1,
+            Err(_) => DEFAULT_MIME_TYPE,
+        },
+        None => DEFAULT_MIME_TYPE,
+    }
+}
+
+// A strong ETag derived from the bytes served, so it is stable across builds and differs
+// between the encodings of the same asset
+pub fn etag(data: &[u8]) -> String {
+    digest::etag_from_sha256(&format!("{:x}", Sha256::digest(data)))
+}
+
+fn escape_glob(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '*' | '?' | '[' | ']' | '{' | '}' => {
+                escaped.push('[');
+                escaped.push(c);
+                escaped.push(']');
+            },
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+const GENERATED_HEADER: &str = "// Generated by static-compress. Do not edit.
+
+pub struct Asset {
+    pub path: &'static str,
+    pub content_type: &'static str,
+    // Of the unencoded `data`
+    pub etag: &'static str,
+    pub data: &'static [u8],
+    // Only where smaller than `data`
+    pub encodings: &'static [Encoding],
+}
+
+pub struct Encoding {
+    pub content_encoding: &'static str,
+    pub etag: &'static str,
+    pub data: &'static [u8],
+}
+
+impl Asset {
+    pub fn encoding(&self, content_encoding: &str) -> Option<&'static Encoding> {
+        self.encodings.iter().find(|e| e.content_encoding == content_encoding)
+    }
+}
+
+pub fn get(path: &str) -> Option<&'static Asset> {
+    ASSETS.binary_search_by(|a| a.path.cmp(path)).ok().map(|i| &ASSETS[i])
+}
+
+";
+
+fn generate_source(files: &[EmbeddedFile]) -> Result<String> {
+    let mut src = String::from(GENERATED_HEADER);
+    src.push_str("pub static ASSETS: &[Asset] = &[\n");
+    for file in files {
+        let source = file.source.to_str().ok_or(ErrorKind::InvalidCharactersInPath)?;
+        src.push_str(&format!("    Asset {{\n        path: {:?},\n        content_type: {:?},\n        etag: {:?},\n",
+                              file.key, file.content_type, file.etag));
+        src.push_str(&format!("        data: include_bytes!({:?}),\n        encodings: &[\n", source));
+        for variant in file.variants.iter() {
+            let path = variant.path.to_str().ok_or(ErrorKind::InvalidCharactersInPath)?;
+            src.push_str(&format!("            Encoding {{ content_encoding: {:?}, etag: {:?}, data: include_bytes!({:?}) }},\n",
+                                  variant.content_encoding, variant.etag, path));
+        }
+        src.push_str("        ],\n    },\n");
+    }
+    src.push_str("];\n");
+
+    Ok(src)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn embeds_smaller_variants_with_their_own_etags() {
+        // Not hidden, unlike the default `.tmp` prefix
+        let root = tempfile::Builder::new().prefix("assets").tempdir().unwrap();
+        let out = tempfile::tempdir().unwrap();
+        let css = "body { color: red; }\n".repeat(100);
+        fs::create_dir_all(root.path().join("css")).unwrap();
+        fs::write(root.path().join("css/site.css"), &css).unwrap();
+        // Compressible, but an already-compressed format
+        fs::write(root.path().join("logo.png"), &css).unwrap();
+        // Too small for any encoding to help
+        fs::write(root.path().join("robots.txt"), "x").unwrap();
+
+        let files = Assets::new(root.path(), &["**/*"]).compress_into(out.path()).unwrap();
+        let keys: Vec<&str> = files.iter().map(|f| f.key.as_str()).collect();
+        assert_eq!(keys, ["css/site.css", "logo.png", "robots.txt"]);
+        assert_eq!(files[0].content_type, "text/css; charset=utf-8");
+        assert_eq!(files[1].content_type, "image/png");
+        assert_eq!(files[2].content_type, "text/plain; charset=utf-8");
+        assert!(files[1].variants.is_empty());
+        assert!(files[2].variants.is_empty());
+
+        let site = &files[0];
+        assert_eq!(site.etag, etag(css.as_bytes()));
+        let encodings: Vec<&str> = site.variants.iter().map(|v| v.content_encoding).collect();
+        assert_eq!(encodings, ["gzip", "br"]);
+        for variant in site.variants.iter() {
+            let encoded = fs::read(&variant.path).unwrap();
+            assert!(encoded.len() < css.len());
+            assert_eq!(variant.etag, etag(&encoded));
+        }
+        assert_ne!(site.variants[0].etag, site.etag);
+        assert_ne!(site.variants[1].etag, site.etag);
+        assert_ne!(site.variants[0].etag, site.variants[1].etag);
+
+        let source = generate_source(&files).unwrap();
+        assert!(source.contains(&format!("content_encoding: \"br\", etag: {:?}", site.variants[1].etag)));
+        assert!(source.contains(&format!("path: \"css/site.css\",\n        content_type: \"text/css; charset=utf-8\",\n        etag: {:?}",
+                                         site.etag)));
+    }
+}
@@ -7,9 +7,28 @@ error_chain! {
         InvalidUsage
         InvalidIncludeFilter
         InvalidCharactersInPath
+        PartialFailure(count: usize) {
+            description("One or more files could not be compressed.")
+            display("{} file(s) could not be compressed", count)
+        }
+        Aborted
     }
     foreign_links {
         Io(::std::io::Error);
         SystemTime(::std::time::SystemTimeError);
     }
 }
+
+pub const EXIT_FAILURE: i32 = 1;
+pub const EXIT_USAGE: i32 = 2; // Also used by clap for argument errors
+pub const EXIT_PARTIAL_FAILURE: i32 = 3;
+
+impl Error {
+    pub fn exit_code(&self) -> i32 {
+        match self.kind() {
+            ErrorKind::InvalidParameterValue(_) | ErrorKind::InvalidUsage | ErrorKind::InvalidIncludeFilter => EXIT_USAGE,
+            ErrorKind::PartialFailure(_) => EXIT_PARTIAL_FAILURE,
+            _ => EXIT_FAILURE,
+        }
+    }
+}
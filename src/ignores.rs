@@ -0,0 +1,165 @@
+// Gitignore-style exclusions, applied with the same nesting as git: patterns in a directory's
+// ignore files apply to everything beneath it, and deeper files take precedence.
+
+extern crate ignore;
+
+use self::ignore::gitignore::{Gitignore, GitignoreBuilder};
+use self::ignore::Match;
+use errors::*;
+use lists::IGNORE_FILES;
+use std::path::Path;
+use std::sync::Arc;
+
+// Shared between the directories being searched in parallel, each of which extends its
+// parent's stack with its own ignore files
+#[derive(Clone, Default)]
+pub struct IgnoreStack {
+    top: Option<Arc<Level>>,
+}
+
+struct Level {
+    matcher: Gitignore,
+    parent: Option<Arc<Level>>,
+}
+
+impl IgnoreStack {
+    /// Loads the ignore files of the directories above `root`, but not of `root` itself.
+    pub fn new(root: &Path, warnings: &mut Vec<String>) -> Result<IgnoreStack> {
+        let ancestors: Vec<&Path> = root.ancestors()
+            .skip(1)
+            .filter(|a| !a.as_os_str().is_empty())
+            .collect();
+
+        let mut stack = IgnoreStack::default();
+        for dir in ancestors.into_iter().rev() {
+            stack = stack.enter(dir, warnings)?;
+        }
+
+        Ok(stack)
+    }
+
+    /// The stack for the contents of `dir`, which is beneath all directories already entered.
+    /// Patterns that can't be parsed are left out, with a warning added to `warnings`.
+    pub fn enter(&self, dir: &Path, warnings: &mut Vec<String>) -> Result<IgnoreStack> {
+        let mut builder = GitignoreBuilder::new(dir);
+        let mut found = false;
+        // Later files take precedence, so the tool-specific file can override the others
+        for name in IGNORE_FILES {
+            let path = dir.join(name);
+            if !path.is_file() {
+                continue;
+            }
+            // Like git, a bad line doesn't stop the rest of the file from applying
+            if let Some(e) = builder.add(&path) {
+                warnings.push(format!("Skipping invalid ignore patterns: {}", e));
+            }
+            found = true;
+        }
+
+        if !found {
+            return Ok(self.clone());
+        }
+
+        let matcher = builder.build().chain_err(|| format!("Invalid ignore file in {}", dir.display()))?;
+        Ok(IgnoreStack {
+            top: Some(Arc::new(Level {
+                matcher,
+                parent: self.top.clone(),
+            })),
+        })
+    }
+
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let mut level = self.top.as_ref();
+        while let Some(current) = level {
+            match current.matcher.matched(path, is_dir) {
+                Match::None => level = current.parent.as_ref(),
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+            }
+        }
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use super::*;
+    use std::fs;
+
+    fn write(path: &Path, contents: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn later_files_take_precedence() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        write(&root.join(".gitignore"), "*.log\nbuild/\n");
+        write(&root.join(".staticcompressignore"), "!keep.log\n");
+
+        let stack = IgnoreStack::new(root, &mut Vec::new()).unwrap().enter(root, &mut Vec::new()).unwrap();
+        assert!(stack.is_ignored(&root.join("debug.log"), false));
+        assert!(!stack.is_ignored(&root.join("keep.log"), false));
+        assert!(stack.is_ignored(&root.join("build"), true));
+        assert!(!stack.is_ignored(&root.join("build"), false));
+        assert!(!stack.is_ignored(&root.join("app.js"), false));
+    }
+
+    #[test]
+    fn deeper_files_take_precedence() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        let sub = root.join("sub");
+        write(&root.join(".gitignore"), "*.map\n");
+        write(&sub.join(".ignore"), "!*.map\n*.txt\n");
+
+        let top = IgnoreStack::new(root, &mut Vec::new()).unwrap().enter(root, &mut Vec::new()).unwrap();
+        let nested = top.enter(&sub, &mut Vec::new()).unwrap();
+        assert!(top.is_ignored(&root.join("app.js.map"), false));
+        assert!(!nested.is_ignored(&sub.join("app.js.map"), false));
+        assert!(nested.is_ignored(&sub.join("notes.txt"), false));
+        // The nested file doesn't apply to its siblings
+        assert!(!top.is_ignored(&root.join("notes.txt"), false));
+    }
+
+    #[test]
+    fn ancestors_apply_but_not_the_root() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        let dist = root.join("dist");
+        write(&root.join(".gitignore"), "dist/\n*.tmp\n");
+        fs::create_dir_all(&dist).unwrap();
+
+        // Searching dist itself: the parent's patterns apply within it
+        let stack = IgnoreStack::new(&dist, &mut Vec::new()).unwrap().enter(&dist, &mut Vec::new()).unwrap();
+        assert!(stack.is_ignored(&dist.join("x.tmp"), false));
+        assert!(!stack.is_ignored(&dist.join("app.js"), false));
+    }
+
+    #[test]
+    fn unchanged_without_ignore_files() {
+        let root = tempfile::tempdir().unwrap();
+        let stack = IgnoreStack::default().enter(root.path(), &mut Vec::new()).unwrap();
+        assert!(stack.top.is_none());
+    }
+
+    #[test]
+    fn invalid_lines_are_skipped_with_a_warning() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        write(&root.join(".ignore"), "*.log\n{a\n*.tmp\n");
+
+        let mut warnings = Vec::new();
+        let stack = IgnoreStack::default().enter(root, &mut warnings).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("line 2"), "{}", warnings[0]);
+        assert!(stack.is_ignored(&root.join("debug.log"), false));
+        assert!(stack.is_ignored(&root.join("x.tmp"), false));
+        assert!(!stack.is_ignored(&root.join("app.js"), false));
+    }
+}
@@ -0,0 +1,818 @@
+#[macro_use] extern crate error_chain;
+#[macro_use] extern crate prettytable;
+#[macro_use] extern crate serde_derive;
+#[macro_use] extern crate stderr;
+extern crate chan;
+extern crate filetime;
+extern crate globset;
+extern crate separator;
+extern crate serde;
+extern crate size;
+
+#[macro_use] pub mod errors;
+mod attributes;
+pub mod bench;
+mod blacklist;
+mod compression;
+mod dedupe;
+pub mod digest;
+pub mod embed;
+mod ignores;
+mod lists;
+mod parallel;
+pub mod manifest;
+mod matcher;
+mod progress;
+pub mod report;
+pub mod serve;
+mod structs;
+mod walk;
+pub mod webserver;
+
+pub use structs::*;
+
+use blacklist::Blacklist;
+use dedupe::{Claim, Deduplicator, Original};
+use digest::Digests;
+use errors::*;
+use matcher::Matcher;
+use parallel::Helpers;
+use progress::Progress;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+const DEBUG_FILTERS: bool = cfg!(debug_assertions);
+#[inline(always)]
+fn debug(message: &str) {
+    if DEBUG_FILTERS {
+        errstln!("{}", message);
+    }
+}
+
+/// Compresses all files matching `filters` according to `params`, returning the merged
+/// statistics for the run. Files that fail to compress are recorded in the report rather
+/// than aborting the run (unless `params.fail_fast` is set).
+pub fn compress_tree(params: Parameters, filters: &[String]) -> Result<Report> {
+    let parameters = Arc::new(params);
+
+    // Set on the first error when fail_fast is used
+    let abort = Arc::new(AtomicBool::new(false));
+    let progress = Arc::new(Progress::new(parameters.threads, parameters.show_progress));
+    let (send_queue, stats_rx, wait_group) = start_workers(&parameters, &abort, &progress);
+
+    let mut include_filters = filters.to_vec();
+    let matcher = build_matcher(&mut include_filters, parameters.case_sensitive)?;
+
+    // Convert filters to paths and deal out conversion jobs
+    let mut stats = dispatch_jobs(send_queue, include_filters, matcher, &parameters, &abort, &progress/*, exclude_filters*/)?;
+
+    // Wait for all jobs to finish
+    wait_group.wait();
+    progress.finish();
+
+    // Merge statistics from all threads
+    while let Ok(thread_stats) = stats_rx.recv() {
+        stats.merge(thread_stats);
+    }
+
+    Ok(Report {
+        statistics: stats,
+        aborted: abort.load(Ordering::SeqCst),
+    })
+}
+
+/// Returns the paths of all files matching `filters`, including those that are already
+/// compressed (and so would be skipped by `compress_tree`).
+pub fn find_files(filters: &[String], case_sensitive: bool) -> Result<Vec<PathBuf>> {
+    let mut include_filters = filters.to_vec();
+    let matcher = build_matcher(&mut include_filters, case_sensitive)?;
+    collect_paths(include_filters, matcher, &Traversal::default())
+}
+
+fn build_matcher(filters: &mut [String], case_sensitive: bool) -> Result<Matcher> {
+    fix_filters(filters);
+    Matcher::new(filters, case_sensitive)
+}
+
+type ThreadParam = std::path::PathBuf;
+
+// Coordination between the workers of a single run
+struct Shared {
+    dedupe: Deduplicator,
+    helpers: Helpers,
+}
+
+fn start_workers(params: &Arc<Parameters>, abort: &Arc<AtomicBool>, progress: &Arc<Progress>)
+    -> (chan::Sender<ThreadParam>, mpsc::Receiver<Statistics>, chan::WaitGroup) {
+    let (tx, rx) = chan::sync::<ThreadParam>(params.threads);
+    let (stats_tx, stats_rx) = std::sync::mpsc::channel::<Statistics>();
+    let wg = chan::WaitGroup::new();
+    let shared = Arc::new(Shared {
+        dedupe: Deduplicator::default(),
+        helpers: Helpers::new(params.threads),
+    });
+
+    for worker in 0..params.threads {
+        let local_params = params.clone();
+        let local_rx = rx.clone();
+        let local_stats_tx = stats_tx.clone();
+        let local_wg = wg.clone();
+        let local_abort = abort.clone();
+        let local_progress = progress.clone();
+        let local_shared = shared.clone();
+        wg.add(1);
+        std::thread::spawn(move || {
+            worker_thread(worker, local_params, local_stats_tx, local_rx, local_abort, local_progress, local_shared);
+            local_wg.done();
+        });
+    }
+
+    (tx, stats_rx, wg)
+}
+
+// Returns the statistics for the matching files that were skipped as already compressed, and
+// any warnings from the search
+fn dispatch_jobs(send_queue: chan::Sender<ThreadParam>, filters: Vec<String>, matcher: Matcher,
+                 params: &Parameters, abort: &AtomicBool, progress: &Progress/*, exclude_filters: Vec<String>*/) -> Result<Statistics> {
+    let blacklist = Blacklist::new(params);
+    let skipped = Mutex::new(Statistics::new());
+    let record_skipped = |path: PathBuf| {
+        // Outputs of this or an earlier run are skipped without being reported as sources
+        if blacklist.is_output(&path) {
+            return;
+        }
+        let mut skipped = skipped.lock().unwrap();
+        skipped.record_skip();
+        if params.record_files {
+            let size = std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+            skipped.record_file(FileRecord {
+                output: path.clone(),
+                source: path,
+                algorithm: params.compressor.name(),
+                input_size: size,
+                output_size: size,
+                duration: Duration::from_secs(0),
+                status: FileStatus::Skipped,
+                modified: 0,
+                sha256: None,
+                integrity: None,
+            });
+        }
+    };
+    let paths = extract_paths(&filters)?;
+
+    if params.largest_first {
+        // Walk and stat everything first, so the most expensive files don't start last
+        let matched = Mutex::new(Vec::new());
+        let warnings = walk::walk(paths, &matcher, &params.traversal, params.threads, &|path: PathBuf| {
+            match blacklist.contains(&path) {
+                true => record_skipped(path),
+                false => {
+                    let (cost, size) = estimated_cost(&path, params);
+                    matched.lock().unwrap().push((cost, size, path));
+                },
+            }
+            Ok(())
+        })?;
+
+        let mut matched = matched.into_inner().unwrap();
+        matched.sort_by_key(|m| std::cmp::Reverse(m.0));
+        progress.add_to_total(matched.len() as u64, matched.iter().map(|m| m.1).sum());
+        for (_, _, path) in matched {
+            if abort.load(Ordering::SeqCst) {
+                break;
+            }
+            send_queue.send(path);
+        }
+
+        let mut skipped = skipped.into_inner().unwrap();
+        warnings.into_iter().for_each(|w| skipped.record_warning(w));
+        return Ok(skipped);
+    }
+
+    let result = walk::walk(paths, &matcher, &params.traversal, params.threads, &|path: PathBuf| {
+        if abort.load(Ordering::SeqCst) {
+            bail!(ErrorKind::Aborted);
+        }
+
+        match blacklist.contains(&path) {
+            true => record_skipped(path),
+            false => {
+                // The total grows as files are found, rather than searching the tree twice
+                if progress.is_interactive() {
+                    progress.add_to_total(1, std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0));
+                }
+                send_queue.send(path);
+            },
+        }
+        Ok(())
+    });
+
+    let warnings = match result {
+        Err(Error(ErrorKind::Aborted, _)) => Vec::new(),
+        r => r?,
+    };
+
+    let mut skipped = skipped.into_inner().unwrap();
+    warnings.into_iter().for_each(|w| skipped.record_warning(w));
+    Ok(skipped)
+}
+
+// The number of bytes a file is expected to cost to compress (none if its output is already
+// up-to-date), along with its size
+fn estimated_cost(path: &Path, params: &Parameters) -> (u64, u64) {
+    let src_metadata = match std::fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return (0, 0),
+    };
+    let up_to_date = std::fs::metadata(output_path(path, params)).ok()
+        .map(|dst_metadata| same_mtime(&src_metadata, &dst_metadata))
+        .unwrap_or(false);
+
+    match up_to_date {
+        true => (0, src_metadata.len()),
+        false => (src_metadata.len(), src_metadata.len()),
+    }
+}
+
+fn output_path(src: &Path, params: &Parameters) -> PathBuf {
+    let mut dst_path = src.as_os_str().to_owned();
+    dst_path.push(".");
+    dst_path.push(&params.extension);
+    PathBuf::from(dst_path)
+}
+
+// Like dispatch_jobs, but gathers the matching paths instead
+fn collect_paths(filters: Vec<String>, matcher: Matcher, traversal: &Traversal) -> Result<Vec<PathBuf>> {
+    let matched = Mutex::new(Vec::new());
+    let threads = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    // Only ignore files raise warnings, and they aren't read by the default traversal
+    walk::walk(extract_paths(&filters)?, &matcher, traversal, threads, &|path: PathBuf| {
+        matched.lock().unwrap().push(path);
+        Ok(())
+    })?;
+
+    // The walk finds files in no particular order
+    let mut matched = matched.into_inner().unwrap();
+    matched.sort();
+    Ok(matched)
+}
+
+fn worker_thread(worker: usize, params: Arc<Parameters>, stats_tx: mpsc::Sender<Statistics>, rx: chan::Receiver<ThreadParam>,
+                 abort: Arc<AtomicBool>, progress: Arc<Progress>, shared: Arc<Shared>) {
+    let mut local_stats = Statistics::new();
+
+    // Loop until there are no more tasks
+    while let Some(src) = rx.recv() {
+        if abort.load(Ordering::SeqCst) {
+            // Drain the queue without doing any more work
+            continue;
+        }
+
+        // In a nested function so we can handle errors centrally
+        fn compress_single(src: &ThreadParam, params: &Parameters, local_stats: &mut Statistics,
+                           progress: &Progress, worker: usize, shared: &Shared) -> Result<()> {
+            let dst_path = output_path(src, params);
+            let dst = dst_path.as_path();
+            // Set once this run removes or starts writing `dst`, which must then be cleaned up on failure
+            let replacing = std::cell::Cell::new(false);
+
+            // Again, in a scope for error handling
+            |local_stats: &mut Statistics| -> Result<()> {
+                    let src_metadata = std::fs::metadata(src)?;
+                    // Only recorded in the manifest and report, so a pre-1970 mtime is no reason to fail
+                    let src_seconds = src_metadata.modified()?.duration_since(std::time::UNIX_EPOCH)
+                        .map(|d| d.as_secs())
+                        .unwrap_or(0);
+                    let src_mtime = filetime::FileTime::from_last_modification_time(&src_metadata);
+                    progress.file_started(worker, src);
+
+                    if params.traversal.link_symlinks && src.is_symlink() {
+                        let created = symlink_output(src, dst, params)?;
+                        local_stats.record_symlink();
+                        if params.record_files {
+                            local_stats.record_file(FileRecord {
+                                source: src.clone(),
+                                output: dst.to_path_buf(),
+                                algorithm: params.compressor.name(),
+                                input_size: src_metadata.len(),
+                                output_size: std::fs::metadata(dst).map(|m| m.len()).unwrap_or(0),
+                                duration: Duration::from_secs(0),
+                                status: FileStatus::Symlinked,
+                                modified: src_seconds,
+                                sha256: None,
+                                integrity: None,
+                            });
+                        }
+                        progress.file_finished(worker, src, src_metadata.len(), created, Some("symlinked"));
+                        return Ok(());
+                    }
+
+                    // Don't compress files that are already compressed that haven't changed
+                    if let Ok(dst_metadata) = std::fs::metadata(dst) {
+                        // The destination already exists
+                        let up_to_date = same_mtime(&src_metadata, &dst_metadata)
+                            || (params.dedupe && dedupe::shares_content(src, dst, &dst_metadata)?);
+                        match up_to_date {
+                            true => {
+                                local_stats.update(src_metadata.len(), dst_metadata.len(), false);
+                                let mut digests = None;
+                                if params.sidecars && sidecars_stale(src, dst, params, &src_metadata) {
+                                    let d = Digests::of_files(src, dst, &params.integrity)?;
+                                    write_sidecars(src, dst, params, &d, &src_metadata)?;
+                                    digests = Some(d);
+                                }
+                                if params.record_files {
+                                    local_stats.record_file(FileRecord {
+                                        source: src.clone(),
+                                        output: dst.to_path_buf(),
+                                        algorithm: params.compressor.name(),
+                                        input_size: src_metadata.len(),
+                                        output_size: dst_metadata.len(),
+                                        duration: Duration::from_secs(0),
+                                        status: FileStatus::UpToDate,
+                                        modified: src_seconds,
+                                        sha256: digests.as_ref().map(|d| d.sha256.clone()),
+                                        integrity: digests.and_then(|d| d.integrity),
+                                    });
+                                }
+                                // No need to recompress
+                                progress.file_finished(worker, src, src_metadata.len(), false, None);
+                                return Ok(());
+                            },
+                            false => {
+                                // Return an error if we can't remove the file
+                                std::fs::remove_file(dst)?;
+                            }
+                        };
+                    }
+                    replacing.set(true);
+
+                    let lead = match params.dedupe {
+                        true => match shared.dedupe.claim(digest::sha256_file(src)?) {
+                            Claim::Lead(lead) => Some(lead),
+                            Claim::Duplicate(Some(original)) => {
+                                let hardlinked = dedupe::link_output(&original, dst, src_mtime)?;
+                                if !hardlinked {
+                                    if params.preserve_attributes {
+                                        attributes::copy_attributes(src, &src_metadata, dst, &params.xattrs);
+                                    }
+                                    copy_times(&src_metadata, dst);
+                                }
+                                local_stats.update(src_metadata.len(), original.output_size, true);
+                                local_stats.record_dedupe(hardlinked, original.output_size, original.duration);
+                                if params.record_files {
+                                    local_stats.record_file(FileRecord {
+                                        source: src.clone(),
+                                        output: dst.to_path_buf(),
+                                        algorithm: original.algorithm,
+                                        input_size: src_metadata.len(),
+                                        output_size: original.output_size,
+                                        duration: Duration::from_secs(0),
+                                        status: FileStatus::Deduplicated,
+                                        modified: src_seconds,
+                                        sha256: original.digests.as_ref().map(|d| d.sha256.clone()),
+                                        integrity: original.digests.as_ref().and_then(|d| d.integrity.clone()),
+                                    });
+                                }
+                                if let (true, Some(ref digests)) = (params.sidecars, &original.digests) {
+                                    write_sidecars(src, dst, params, digests, &src_metadata)?;
+                                }
+                                let label = if hardlinked { "linked" } else { "copied" };
+                                progress.file_finished(worker, src, src_metadata.len(), true, Some(label));
+                                return Ok(());
+                            },
+                            // The identical source failed to compress, so try again with this one
+                            Claim::Duplicate(None) => None,
+                        },
+                        false => None,
+                    };
+
+                    let needs_digests = params.hash_outputs || params.etags || !params.integrity.is_empty();
+                    let start = Instant::now();
+                    let (algorithm, label, digests) = match params.best_of {
+                        Some(ref best_of) => {
+                            let winner = best_of.compress(src.as_path(), dst, params.quality)?;
+                            local_stats.record_win(winner);
+                            (winner, Some(winner), None)
+                        },
+                        None if parallel::supports(params.compressor)
+                            && params.parallel_threshold.map(|t| src_metadata.len() >= t).unwrap_or(false) => {
+                            let (digests, split) = parallel::compress_large(params.compressor, src.as_path(), dst,
+                                                                            params.quality, &params.integrity, &shared.helpers)?;
+                            (params.compressor.name(), Some("parallel").filter(|_| split), Some(digests).filter(|_| needs_digests))
+                        },
+                        // webp is compressed by an external tool, so can't be digested in passing
+                        None if needs_digests && params.compressor != CompressionAlgorithm::WebP => {
+                            let digests = compression::compress_file_digested(&params.compressor, src.as_path(), dst,
+                                                                              params.quality, &params.integrity)?;
+                            (params.compressor.name(), None, Some(digests))
+                        },
+                        None => {
+                            params.compressor.compress(src.as_path(), dst, params.quality)?;
+                            (params.compressor.name(), None, None)
+                        }
+                    };
+                    let duration = start.elapsed();
+                    let digests = match digests {
+                        None if needs_digests => Some(Digests::of_files(src, dst, &params.integrity)?),
+                        d => d,
+                    };
+                    let dst_metadata = std::fs::metadata(dst)?;
+                    local_stats.update(src_metadata.len(), dst_metadata.len(), true);
+                    if params.record_files {
+                        local_stats.record_file(FileRecord {
+                            source: src.clone(),
+                            output: dst.to_path_buf(),
+                            algorithm,
+                            input_size: src_metadata.len(),
+                            output_size: dst_metadata.len(),
+                            duration,
+                            status: FileStatus::Compressed,
+                            modified: src_seconds,
+                            sha256: digests.as_ref().map(|d| d.sha256.clone()),
+                            integrity: digests.as_ref().and_then(|d| d.integrity.clone()),
+                        });
+                    }
+                    if params.preserve_attributes {
+                        attributes::copy_attributes(src, &src_metadata, dst, &params.xattrs);
+                    }
+                    copy_times(&src_metadata, dst);
+                    if let (true, Some(ref digests)) = (params.sidecars, &digests) {
+                        write_sidecars(src, dst, params, digests, &src_metadata)?;
+                    }
+                    if let Some(lead) = lead {
+                        let tagged = dedupe::tag_output(dst, &lead.content_hash);
+                        lead.complete(Original {
+                            output: dst.to_path_buf(),
+                            modified: src_mtime,
+                            output_size: dst_metadata.len(),
+                            duration,
+                            algorithm,
+                            digests: digests.clone(),
+                            tagged,
+                        });
+                    }
+                    progress.file_finished(worker, src, src_metadata.len(), true, label);
+
+                    Ok(())
+                }(local_stats)
+                .inspect_err(|_| {
+                    // Try deleting the invalid destination file, but don't care if we can't. An
+                    // existing output that was left alone is still valid.
+                    if replacing.get() {
+                        std::fs::remove_file(dst).unwrap_or_default();
+                    }
+                    let src_size = std::fs::metadata(src).map(|m| m.len()).unwrap_or(0);
+                    progress.file_finished(worker, src, src_size, false, None);
+                    if params.record_files {
+                        local_stats.record_file(FileRecord {
+                            source: src.clone(),
+                            output: dst.to_path_buf(),
+                            algorithm: params.compressor.name(),
+                            input_size: src_size,
+                            output_size: 0,
+                            duration: Duration::from_secs(0),
+                            status: FileStatus::Failed,
+                            modified: 0,
+                            sha256: None,
+                            integrity: None,
+                        });
+                    }
+                })
+        }
+
+        if let Err(e) = compress_single(&src, &params, &mut local_stats, &progress, worker, &shared) {
+            progress.error(&format!("Error compressing {}: {}", src.to_string_lossy(), e));
+            local_stats.record_error(src, &e);
+            if params.fail_fast {
+                abort.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+
+    // Lend this thread to any large files still being compressed
+    shared.helpers.retire();
+
+    if stats_tx.send(local_stats).is_err() {
+        errstln!("Error compiling statistics!");
+    }
+}
+
+// Points `dst` at the output for the symlink's own target, which is compressed only if it is
+// matched itself. Returns false if `dst` already pointed there.
+fn symlink_output(src: &Path, dst: &Path, params: &Parameters) -> Result<bool> {
+    let target = output_path(&std::fs::read_link(src)?, params);
+    if std::fs::read_link(dst).map(|existing| existing == target).unwrap_or(false) {
+        return Ok(false);
+    }
+
+    if dst.symlink_metadata().is_ok() {
+        std::fs::remove_file(dst)?;
+    }
+    symlink_file(&target, dst).chain_err(|| format!("Error creating symlink {}", dst.display()))?;
+    Ok(true)
+}
+
+#[cfg(unix)]
+fn symlink_file(target: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn symlink_file(target: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::windows::fs::symlink_file(target, link)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn symlink_file(_target: &Path, _link: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Other, "Symlinks are not supported on this platform"))
+}
+
+// Outputs are given their source's access and modification times, so a changed source can be
+// told apart from its stale output
+fn copy_times(src_metadata: &std::fs::Metadata, dst: &Path) {
+    let accessed = filetime::FileTime::from_last_access_time(src_metadata);
+    let modified = filetime::FileTime::from_last_modification_time(src_metadata);
+    filetime::set_file_times(dst, accessed, modified).unwrap_or_default();
+}
+
+// Compared to the nanosecond, unless the output's filesystem only stores whole seconds
+fn same_mtime(src_metadata: &std::fs::Metadata, dst_metadata: &std::fs::Metadata) -> bool {
+    let src = filetime::FileTime::from_last_modification_time(src_metadata);
+    let dst = filetime::FileTime::from_last_modification_time(dst_metadata);
+    src == dst || (dst.nanoseconds() == 0 && src.unix_seconds() == dst.unix_seconds())
+}
+
+fn sidecar_path(path: &Path, extension: &str) -> PathBuf {
+    let mut sidecar = path.as_os_str().to_owned();
+    sidecar.push(".");
+    sidecar.push(extension);
+    PathBuf::from(sidecar)
+}
+
+// Sidecars share the source's mtime, like the compressed output
+fn sidecars_stale(src: &Path, dst: &Path, params: &Parameters, src_metadata: &std::fs::Metadata) -> bool {
+    let is_stale = |path: PathBuf| {
+        std::fs::metadata(path)
+            .map(|m| !same_mtime(src_metadata, &m))
+            .unwrap_or(true)
+    };
+
+    // Also stale if computed with a different set of --sri algorithms
+    let sri_path = sidecar_path(src, "sri");
+    let sri_algorithms_changed = || {
+        let algorithms: Vec<String> = params.integrity.iter().map(|a| format!("{}-", a.name())).collect();
+        let existing = std::fs::read_to_string(&sri_path).unwrap_or_default();
+        let digests: Vec<&str> = existing.split_whitespace().collect();
+        digests.len() != algorithms.len() || !digests.iter().zip(algorithms.iter()).all(|(d, a)| d.starts_with(a.as_str()))
+    };
+
+    (!params.integrity.is_empty() && (is_stale(sri_path.clone()) || sri_algorithms_changed()))
+        || (params.etags && is_stale(sidecar_path(dst, "etag")))
+}
+
+fn write_sidecars(src: &Path, dst: &Path, params: &Parameters, digests: &Digests, src_metadata: &std::fs::Metadata) -> Result<()> {
+    let write = |path: PathBuf, contents: &str| -> Result<()> {
+        std::fs::write(&path, format!("{}\n", contents))?;
+        copy_times(src_metadata, &path);
+        Ok(())
+    };
+
+    if let Some(ref integrity) = digests.integrity {
+        write(sidecar_path(src, "sri"), integrity)?;
+    }
+    if params.etags {
+        write(sidecar_path(dst, "etag"), &digest::etag_from_sha256(&digests.sha256))?;
+    }
+
+    Ok(())
+}
+
+fn str_search(sorted: &[&str], search_term: &str, case_sensitive: bool) -> std::result::Result<usize, usize> {
+    use std::borrow::Cow;
+
+    let term = match case_sensitive {
+        true => Cow::from(search_term),
+        false => if search_term.chars().all(char::is_lowercase) { Cow::from(search_term) } else { Cow::from(search_term.to_lowercase()) },
+    };
+
+    sorted.binary_search_by(|probe| (*probe).cmp(term.as_ref()))
+}
+
+// Names need not be valid UTF-8, but everything we compare them against is ASCII, which a
+// lossy conversion leaves intact
+fn is_hidden(path: &Path) -> bool {
+    match path.file_name() {
+        Some(x) => x.to_string_lossy().starts_with('.'),
+        None => false
+    }
+}
+
+// Prepends ./ to relative paths
+fn fix_filters(filters: &mut [String]) {
+    for filter in filters.iter_mut() {
+        match filter.chars().next().expect("Received blank filter!") {
+            '.' | '/' => continue,
+            _ => *filter = format!("./{}", filter) // Use un-prefixed path
+        }
+    }
+}
+
+// Given a list of filters, extracts the directories that should be searched along with the
+// depth to which they should be recursed (None if unlimited).
+use std::collections::HashMap;
+fn extract_paths(filters: &Vec<String>) -> Result<HashMap<PathBuf, Option<usize>>> {
+    use std::iter::FromIterator;
+
+    let mut dirs = HashMap::<PathBuf, Option<usize>>::new();
+
+    {
+        let insert_path = &mut |filter: &String, dir: PathBuf, depth: Option<usize>| {
+            debug(&format!("filter {} mapped to search {} (depth {:?})", filter, dir.display(), depth));
+            // The deepest of the filters searching the same directory wins
+            let merged = match dirs.get(&dir) {
+                Some(&Some(existing)) => depth.map(|d| std::cmp::max(d, existing)),
+                Some(&None) => None,
+                None => depth,
+            };
+            dirs.insert(dir, merged);
+        };
+
+        for filter in filters {
+            // Take everything until the first expression
+            let mut last_char = None::<char>;
+            let dir;
+            {
+                let partial = filter.chars().take_while(|c| match c {
+                    &'?' | &'*' | &'{' | &'[' => false,
+                    c => { last_char = Some(*c); true }
+                });
+                dir = String::from_iter(partial);
+            }
+
+            // Each separator after the first expression is a level below the searched directory
+            let expression = &filter[dir.len()..];
+            let depth = match expression.contains("**") {
+                true => None,
+                false => Some(expression.matches('/').count() + 1),
+            };
+
+            let dir = match dir.chars().next() {
+                Some(c) => match c {
+                    '.' | '/' => PathBuf::from(dir),
+                    _ => {
+                        let mut pb = PathBuf::from("./");
+                        pb.push(dir);
+                        pb
+                    }
+                },
+                None => {
+                    insert_path(filter, PathBuf::from("./"), depth);
+                    continue;
+                }
+            };
+
+            if dir.to_string_lossy().ends_with(filter.as_str()) {
+                // The "dir" is actually a full path to a single file, return it as-is.
+                insert_path(filter, dir, Some(0));
+                continue;
+            }
+
+            if last_char == Some('/') {
+                // Dir is a already a directory, return it as-is.
+                insert_path(filter, dir, depth);
+                continue;
+            }
+
+            // We need to extract the directory from the path we have
+            let dir = match dir.parent() {
+                Some(parent) => parent.to_path_buf(),
+                None => PathBuf::from("./"),
+            };
+
+            insert_path(filter, dir, depth);
+        }
+    }
+
+    debug(&format!("final search paths: {:?}", dirs));
+
+    Ok(dirs)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use super::*;
+    use std::fs;
+
+    // Not hidden, unlike the default `.tmp` prefix
+    fn tree(files: &[(&str, &str)]) -> tempfile::TempDir {
+        let dir = tempfile::Builder::new().prefix("tree").tempdir().unwrap();
+        for &(name, contents) in files {
+            let path = dir.path().join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        dir
+    }
+
+    fn paths(filters: &[&str]) -> HashMap<PathBuf, Option<usize>> {
+        extract_paths(&filters.iter().map(|f| f.to_string()).collect()).unwrap()
+    }
+
+    #[test]
+    fn extracts_a_depth_for_each_root() {
+        let roots = paths(&["/srv/www/*.js", "/srv/www/js/**/*.js", "/srv/www/app.json", "src/*/*.css", "*.html",
+                            "/srv/docs/a*.md"]);
+        assert_eq!(roots.len(), 6);
+        assert_eq!(roots[Path::new("/srv/www")], Some(1));
+        assert_eq!(roots[Path::new("/srv/www/js")], None);
+        // A single file
+        assert_eq!(roots[Path::new("/srv/www/app.json")], Some(0));
+        assert_eq!(roots[Path::new("./src")], Some(2));
+        assert_eq!(roots[Path::new("./")], Some(1));
+        assert_eq!(roots[Path::new("/srv/docs")], Some(1));
+    }
+
+    #[test]
+    fn merges_the_depths_of_a_shared_root() {
+        // The deepest filter wins, and an unlimited one most of all
+        assert_eq!(paths(&["/srv/*.js", "/srv/*/*/*.css", "/srv/*/*.html"])[Path::new("/srv")], Some(3));
+        assert_eq!(paths(&["/srv/*.js", "/srv/**/*.css", "/srv/*/*.html"])[Path::new("/srv")], None);
+    }
+
+    #[test]
+    fn compresses_a_tree_and_records_failures() {
+        let html = "<p>hello</p>\n".repeat(100);
+        let dir = tree(&[("a.html", &html), ("b.css", "body {}\n"), ("c.png", "")]);
+        // Can't be replaced by an output
+        fs::create_dir(dir.path().join("b.css.gz")).unwrap();
+        let filters = vec![format!("{}/*", dir.path().display())];
+
+        let mut params = Parameters::new(CompressionAlgorithm::GZip);
+        params.threads = 2;
+        let report = compress_tree(params, &filters).unwrap();
+        let stats = &report.statistics;
+        assert!(!report.aborted);
+        assert_eq!(stats.file_count_now(), 1);
+        assert_eq!(stats.uncompressed_size_now(), html.len() as u64);
+        assert_eq!(stats.compressed_size_now(), fs::metadata(dir.path().join("a.html.gz")).unwrap().len());
+        assert!(stats.records().is_empty());
+        assert_eq!(stats.errors().len(), 1);
+        assert_eq!(stats.errors()[0].path, dir.path().join("b.css"));
+
+        // Already up-to-date
+        let mut params = Parameters::new(CompressionAlgorithm::GZip);
+        params.record_files = true;
+        let stats = compress_tree(params, &filters).unwrap().statistics;
+        assert_eq!(stats.file_count_now(), 0);
+        assert_eq!(stats.file_count(), 1);
+        assert_eq!(stats.compressed_size(), fs::metadata(dir.path().join("a.html.gz")).unwrap().len());
+        let status = |name: &str| stats.records().iter().find(|r| r.source == dir.path().join(name)).map(|r| r.status);
+        assert_eq!(stats.records().len(), 3);
+        assert!(status("a.html") == Some(FileStatus::UpToDate));
+        assert!(status("b.css") == Some(FileStatus::Failed));
+        assert!(status("c.png") == Some(FileStatus::Skipped));
+    }
+
+    #[test]
+    fn records_the_winning_candidates() {
+        let html = "<p>hello</p>\n".repeat(100);
+        let dir = tree(&[("a.html", &html), ("b.css", "body {}\n")]);
+        let filters = vec![format!("{}/*", dir.path().display())];
+
+        let mut params = Parameters::new(CompressionAlgorithm::Brotli);
+        params.best_of = Some(BestOf::for_format(&CompressionAlgorithm::Brotli).unwrap());
+        let stats = compress_tree(params, &filters).unwrap().statistics;
+        assert_eq!(stats.file_count_now(), 2);
+        assert_eq!(stats.wins().values().sum::<u32>(), 2);
+        assert!(stats.wins().keys().all(|name| name.starts_with("brotli-")));
+    }
+
+    #[test]
+    fn keeps_up_to_date_outputs_when_sidecars_fail() {
+        let dir = tree(&[("a.html", &"<p>hello</p>\n".repeat(100))]);
+        let filters = vec![format!("{}/*", dir.path().display())];
+        let params = || {
+            let mut params = Parameters::new(CompressionAlgorithm::GZip);
+            params.sidecars = true;
+            params.etags = true;
+            params
+        };
+        let stats = compress_tree(params(), &filters).unwrap().statistics;
+        assert!(stats.errors().is_empty());
+
+        // Can't be rewritten
+        let etag = dir.path().join("a.html.gz.etag");
+        fs::remove_file(&etag).unwrap();
+        fs::create_dir(&etag).unwrap();
+        let stats = compress_tree(params(), &filters).unwrap().statistics;
+        assert_eq!(stats.errors().len(), 1);
+        assert!(dir.path().join("a.html.gz").exists());
+    }
+}
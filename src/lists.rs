@@ -1,15 +1,183 @@
-pub const COMP_EXTS: &'static [&'static str] = &[
+// Formats that are already compressed, and so are skipped unless named with --compress-ext
+pub const COMP_EXTS: &[(&str, &[&str])] = &[
+    ("archive", ARCHIVE_EXTS),
+    ("stream", STREAM_EXTS),
+    ("image", IMAGE_EXTS),
+    ("audio", AUDIO_EXTS),
+    ("video", VIDEO_EXTS),
+    ("font", FONT_EXTS),
+    ("document", DOCUMENT_EXTS),
+    ("package", PACKAGE_EXTS),
+];
+
+pub const ARCHIVE_EXTS: &[&str] = &[
     "7z",
+    "arj",
+    "cab",
+    "lzh",
+    "rar",
+    "sfx",
+    "tbz",
+    "tbz2",
+    "tgz",
+    "txz",
+    "tzst",
+    "zip",
+    "zpaq",
+];
+
+// Single-file compression formats, including those written by static-compress itself
+pub const STREAM_EXTS: &[&str] = &[
     "br",
     "bz2",
     "gz",
-    "lzh",
+    "lz",
+    "lz4",
     "lzma",
+    "lzo",
     "lzx",
-    "rar",
-    "sfx",
+    "sz",
     "xz",
-    "zip",
-    "zpaq",
+    "z",
+    "zst",
     "zz",
 ];
+
+pub const IMAGE_EXTS: &[&str] = &[
+    "avif",
+    "gif",
+    "heic",
+    "heif",
+    "jpeg",
+    "jpg",
+    "jxl",
+    "png",
+    "webp",
+];
+
+pub const AUDIO_EXTS: &[&str] = &[
+    "aac",
+    "flac",
+    "m4a",
+    "mp3",
+    "oga",
+    "ogg",
+    "opus",
+];
+
+pub const VIDEO_EXTS: &[&str] = &[
+    "avi",
+    "m4v",
+    "mkv",
+    "mov",
+    "mp4",
+    "mpeg",
+    "mpg",
+    "ogv",
+    "webm",
+];
+
+pub const FONT_EXTS: &[&str] = &[
+    "woff",
+    "woff2",
+];
+
+// Zip containers
+pub const DOCUMENT_EXTS: &[&str] = &[
+    "docx",
+    "epub",
+    "odp",
+    "ods",
+    "odt",
+    "pptx",
+    "xlsx",
+];
+
+pub const PACKAGE_EXTS: &[&str] = &[
+    "apk",
+    "crx",
+    "deb",
+    "jar",
+    "nupkg",
+    "rpm",
+    "war",
+    "whl",
+    "xpi",
+];
+
+// The default extensions of compressed outputs
+pub const OUTPUT_EXTS: &[&str] = &[
+    "br",
+    "gz",
+    "webp",
+    "zst",
+];
+
+// Written alongside sources and outputs by --sri and --etag
+pub const SIDECAR_EXTS: &[&str] = &[
+    "etag",
+    "sri",
+];
+
+// Ends the hidden temporary files written by --best-of and --manifest, which a concurrent
+// search must not pick up
+pub const TEMP_SUFFIX: &str = ".static-compress.tmp";
+
+// Never searched unless named with --include-hidden-dir, even with --hidden
+pub const VCS_DIRS: &[&str] = &[
+    ".bzr",
+    ".git",
+    ".hg",
+    ".jj",
+    ".svn",
+];
+
+// Read in each directory by --ignore-files, in increasing order of precedence
+pub const IGNORE_FILES: &[&str] = &[
+    ".gitignore",
+    ".ignore",
+    ".staticcompressignore",
+];
+
+pub const DEFAULT_MIME_TYPE: &str = "application/octet-stream";
+
+// Sorted by extension for binary search
+pub const MIME_TYPES: &[(&str, &str)] = &[
+    ("atom", "application/atom+xml"),
+    ("avif", "image/avif"),
+    ("bmp", "image/bmp"),
+    ("css", "text/css; charset=utf-8"),
+    ("csv", "text/csv; charset=utf-8"),
+    ("eot", "application/vnd.ms-fontobject"),
+    ("gif", "image/gif"),
+    ("htm", "text/html; charset=utf-8"),
+    ("html", "text/html; charset=utf-8"),
+    ("ico", "image/x-icon"),
+    ("jpeg", "image/jpeg"),
+    ("jpg", "image/jpeg"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("json", "application/json"),
+    ("jsonld", "application/ld+json"),
+    ("map", "application/json"),
+    ("md", "text/markdown; charset=utf-8"),
+    ("mjs", "text/javascript; charset=utf-8"),
+    ("mp3", "audio/mpeg"),
+    ("mp4", "video/mp4"),
+    ("ogg", "audio/ogg"),
+    ("otf", "font/otf"),
+    ("pdf", "application/pdf"),
+    ("png", "image/png"),
+    ("rss", "application/rss+xml"),
+    ("svg", "image/svg+xml"),
+    ("ttf", "font/ttf"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("wasm", "application/wasm"),
+    ("wav", "audio/wav"),
+    ("webm", "video/webm"),
+    ("webmanifest", "application/manifest+json"),
+    ("webp", "image/webp"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("xhtml", "application/xhtml+xml"),
+    ("xml", "application/xml"),
+];
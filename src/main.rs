@@ -1,46 +1,50 @@
-#[macro_use] extern crate error_chain;
-#[macro_use] extern crate prettytable;
-#[macro_use] extern crate stderr;
-extern crate chan;
 extern crate clap;
-extern crate filetime;
-extern crate globset;
-extern crate separator;
-extern crate size;
-
-#[macro_use] mod errors;
-mod compression;
-mod lists;
-mod structs;
-
-use clap::{App, Arg};
-use errors::*;
-use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
-use lists::*;
+extern crate error_chain;
+#[macro_use] extern crate stderr;
+extern crate static_compress;
+
+use clap::{App, Arg, ArgMatches};
+use error_chain::ChainedError;
+use static_compress::*;
+use static_compress::bench::{self, Benchmark};
+use static_compress::digest::SriAlgorithm;
+use static_compress::errors::*;
+use static_compress::manifest::Manifest;
+use static_compress::serve::Server;
+use static_compress::webserver::{generate_config, generate_lighttpd_script, Variant, WebServer};
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use std::sync::mpsc;
-use structs::*;
 
-const DEBUG_FILTERS: bool = cfg!(debug_assertions);
-#[inline(always)]
-fn debug(message: &str) {
-    if DEBUG_FILTERS {
-        errstln!("{}", message);
+fn main() {
+    if let Err(e) = run() {
+        errst!("{}", e.display_chain());
+        std::process::exit(e.exit_code());
     }
 }
 
-quick_main!(run);
-
 fn run() -> Result<()> {
+    let filters_arg = Arg::new("filters")
+        .value_name("FILTER")
+        .multiple_occurrences(true)
+        .required(true);
+    let nocase_arg = Arg::new("nocase")
+        .short('i')
+        .long("case-insensitive")
+        .takes_value(false)
+        .help("Use case-insensitive pattern matching.");
+    let no_progress_arg = Arg::new("no-progress")
+        .long("no-progress")
+        .takes_value(false)
+        .help("Do not display progress or list files as they are compressed.");
+
     let matches = App::new("static-compress")
         .version("0.3.3")
         .about("Create statically-compresed copies of matching files")
         .author("Mahmoud Al-Qudsi, NeoSmart Technologies")
+        .subcommand_negates_reqs(true)
         .arg(Arg::new("compressor")
             .short('c')
             .long("compressor")
-            .value_name("[brotli|gzip|zopfli|webp]")
+            .value_name("[brotli|gzip|zopfli|zstd|webp]")
             .help("The compressor to use (default: gzip)")
             .takes_value(true))
         .arg(Arg::new("threads")
@@ -49,350 +53,426 @@ fn run() -> Result<()> {
             .value_name("COUNT")
             .help("The number of simultaneous compressions (default: number of cores)")
             .takes_value(true))
-        .arg(Arg::new("filters")
-            .value_name("FILTER")
-            .multiple_occurrences(true)
-            .required(true))
+        .arg(Arg::new("parallel-threshold")
+            .long("parallel-threshold")
+            .value_name("MIB")
+            .takes_value(true)
+            .help("Split gzip and zstd compression of files at least this large across idle threads (default: 16)"))
+        .arg(Arg::new("no-parallel")
+            .long("no-parallel")
+            .takes_value(false)
+            .help("Never split the compression of a single file across threads."))
+        .arg(Arg::new("largest-first")
+            .long("largest-first")
+            .takes_value(false)
+            .help("Find and stat all matching files before compressing, starting with the largest"))
+        .arg(filters_arg.clone())
         .arg(Arg::new("ext")
             .short('e')
             .value_name("EXT")
             .long("extension")
-            .help("The extension to use for compressed files (default: gz, br, or webp)"))
+            .help("The extension to use for compressed files (default: gz, br, zst or webp)"))
         .arg(Arg::new("quality")
              .short('q')
              .long("quality")
              .takes_value(true)
              .help("A quality parameter to be passed to the encoder. Algorithm-specific."))
+        .arg(Arg::new("best-of")
+             .short('b')
+             .long("best-of")
+             .takes_value(false)
+             .help("Try every available encoder for the output format and keep the smallest result."))
         .arg(Arg::new("quiet")
              .long("quiet")
              .takes_value(false)
              .help("Does not display progress or end-of-run summary table."))
-        .arg(Arg::new("no-progress")
-             .long("no-progress")
+        .arg(no_progress_arg.clone())
+        .arg(Arg::new("report")
+             .long("report")
+             .value_names(&["json|csv", "PATH"])
+             .number_of_values(2)
+             .help("Write a machine-readable summary and per-file report to PATH."))
+        .arg(Arg::new("manifest")
+             .long("manifest")
+             .value_name("PATH")
+             .takes_value(true)
+             .help("Create or update a JSON manifest of every compressed variant with its size, SHA-256 and mtime."))
+        .arg(Arg::new("sri")
+             .long("sri")
+             .value_name("sha256|sha384|sha512")
+             .multiple_occurrences(true)
+             .help("Compute a Subresource Integrity digest of each compressed source file."))
+        .arg(Arg::new("etag")
+             .long("etag")
              .takes_value(false)
-             .help("Do not list files as they are compressed."))
+             .help("Compute a strong ETag for each compressed file."))
+        .arg(Arg::new("digests")
+             .long("digests")
+             .value_name("sidecar|manifest")
+             .takes_value(true)
+             .help("Where to write --sri and --etag values (default: manifest with --manifest, otherwise sidecar)"))
         .arg(Arg::new("no-summary")
              .long("no-summary")
              .takes_value(false)
              .help("Hide end-of-run statistics summary."))
-        .arg(Arg::new("nocase")
-             .short('i')
-             .long("case-insensitive")
+        .arg(Arg::new("dedupe")
+             .long("dedupe")
+             .takes_value(false)
+             .help("Compress files with identical contents once, hardlinking (or copying) the output for the rest."))
+        .arg(Arg::new("no-preserve")
+             .long("no-preserve")
+             .takes_value(false)
+             .help("Create outputs with default permissions and ownership, rather than those of the source file"))
+        .arg(Arg::new("xattr")
+             .long("xattr")
+             .value_name("NAME")
+             .takes_value(true)
+             .multiple_occurrences(true)
+             .help("An extended attribute to copy to outputs, or a prefix like user.* (default: security.selinux). May be repeated."))
+        .arg(Arg::new("fail-fast")
+             .long("fail-fast")
+             .takes_value(false)
+             .help("Stop compressing further files after the first error."))
+        .arg(nocase_arg.clone())
+        .arg(Arg::new("hidden")
+             .long("hidden")
+             .takes_value(false)
+             .help("Search hidden files and directories, other than version control metadata such as .git"))
+        .arg(Arg::new("include-hidden-dir")
+             .long("include-hidden-dir")
+             .value_name("NAME")
+             .takes_value(true)
+             .multiple_occurrences(true)
+             .allow_invalid_utf8(true)
+             .help("Search hidden directories with this name, e.g. .well-known. May be repeated."))
+        .arg(Arg::new("ignore-files")
+             .long("ignore-files")
+             .takes_value(false)
+             .help("Skip files excluded by .gitignore, .ignore or .staticcompressignore files"))
+        .arg(Arg::new("follow-symlinks")
+             .long("follow-symlinks")
+             .takes_value(false)
+             .overrides_with("no-follow-symlinks")
+             .help("Search symlinked directories and compress symlinked files (default)"))
+        .arg(Arg::new("no-follow-symlinks")
+             .long("no-follow-symlinks")
              .takes_value(false)
-             .help("Use case-insensitive pattern matching."))
+             .overrides_with("follow-symlinks")
+             .help("Skip symlinks found while searching"))
+        .arg(Arg::new("symlink-outputs")
+             .long("symlink-outputs")
+             .takes_value(false)
+             .help("For a symlinked file, create a symlink to the compressed target instead of a compressed copy"))
+        .arg(Arg::new("max-depth")
+             .long("max-depth")
+             .value_name("DEPTH")
+             .takes_value(true)
+             .help("Descend at most this many directories below the searched path (default: as deep as the filters can match)"))
+        .arg(Arg::new("one-file-system")
+             .long("one-file-system")
+             .takes_value(false)
+             .help("Don't descend into directories on other filesystems, such as mounted network shares"))
+        .arg(Arg::new("skip-ext")
+             .long("skip-ext")
+             .value_name("EXT")
+             .takes_value(true)
+             .multiple_occurrences(true)
+             .use_value_delimiter(true)
+             .help("Never compress files with this extension, in addition to already-compressed formats. May be repeated or comma-separated."))
+        .arg(Arg::new("compress-ext")
+             .long("compress-ext")
+             .value_name("EXT")
+             .takes_value(true)
+             .multiple_occurrences(true)
+             .use_value_delimiter(true)
+             .help("Compress files with this extension even though it is a known compressed format. May be repeated or comma-separated."))
+        .arg(Arg::new("blacklist")
+             .long("blacklist")
+             .value_name("FILE")
+             .takes_value(true)
+             .help("Read extensions to skip from FILE, one per line (prefix with ! to compress them instead)"))
         /*.arg(Arg::new("excludes")
             .short('x')
             .value_name("FILTER")
             .long("exclude")
             .multiple(true)
             .help("Exclude files matching this glob expression"))*/
+        .subcommand(App::new("bench")
+            .about("Compare every algorithm and quality level on a sample of matching files, without writing any output")
+            .arg(Arg::new("samples")
+                 .short('n')
+                 .long("samples")
+                 .value_name("COUNT")
+                 .takes_value(true)
+                 .help("The maximum number of matching files to sample (default: 100)"))
+            .arg(no_progress_arg)
+            .arg(nocase_arg)
+            .arg(filters_arg))
+        .subcommand(App::new("config")
+            .about("Print a web server configuration snippet for serving the compressed files")
+            .arg(Arg::new("server")
+                 .value_name("nginx|apache|caddy|lighttpd")
+                 .required(true))
+            .arg(Arg::new("compressor")
+                 .short('c')
+                 .long("compressor")
+                 .value_name("[brotli|gzip|zopfli|zstd]")
+                 .multiple_occurrences(true)
+                 .help("A compressor whose output is served, in order of preference (default: gzip)"))
+            .arg(Arg::new("ext")
+                 .short('e')
+                 .long("extension")
+                 .value_name("EXT")
+                 .multiple_occurrences(true)
+                 .help("The extension used with the compressor in the same position (default: gz, br or zst)"))
+            .arg(Arg::new("location")
+                 .long("location")
+                 .value_name("PATH")
+                 .takes_value(true)
+                 .help("The URL prefix the compressed files are served under (default: /)"))
+            .arg(Arg::new("lua-script")
+                 .long("lua-script")
+                 .takes_value(false)
+                 .help("Print the Lua script used by the lighttpd configuration instead")))
+        .subcommand(App::new("serve")
+            .about("Serve a directory over HTTP, negotiating precompressed variants, for local testing")
+            .arg(Arg::new("listen")
+                 .short('l')
+                 .long("listen")
+                 .value_name("ADDRESS")
+                 .takes_value(true)
+                 .help("The address to listen on (default: 127.0.0.1:8080)"))
+            .arg(Arg::new("root")
+                 .value_name("DIR")
+                 .help("The directory to serve (default: .)")))
         .get_matches();
 
-    fn get_parameter<'a, T>(matches: &clap::ArgMatches, name: &'static str, default_value: T) -> Result<T>
-        where T: std::str::FromStr
-    {
-        match matches.value_of(name) {
-            Some(v) => {
-                Ok(v.parse().map_err(|_| ErrorKind::InvalidParameterValue(name))?)
-            }
-            None => Ok(default_value),
-        }
+    match matches.subcommand() {
+        Some(("bench", bench_matches)) => return run_bench(bench_matches),
+        Some(("config", config_matches)) => return run_config(config_matches),
+        Some(("serve", serve_matches)) => {
+            let root = Path::new(serve_matches.value_of("root").unwrap_or("."));
+            return Server::new(root).run(serve_matches.value_of("listen").unwrap_or("127.0.0.1:8080"));
+        },
+        _ => {},
     }
 
-    let case_sensitive = !matches.is_present("nocase");
     let compressor = get_parameter(&matches, "compressor", CompressionAlgorithm::GZip)?;
     let show_summary = !matches.contains_id("no-summary") && !matches.contains_id("quiet");
     let show_progress = !matches.contains_id("no-progress") && !matches.contains_id("quiet");
+    let best_of = match matches.is_present("best-of") {
+        true => Some(BestOf::for_format(&compressor).chain_err(|| ErrorKind::InvalidParameterValue("best-of"))?),
+        false => None,
+    };
+
+    let report = match matches.values_of("report") {
+        Some(mut values) => {
+            let format = values.next().ok_or(ErrorKind::InvalidParameterValue("report"))?;
+            let path = values.next().ok_or(ErrorKind::InvalidParameterValue("report"))?;
+            Some((format.parse::<ReportFormat>().map_err(|_| ErrorKind::InvalidParameterValue("report"))?,
+                  PathBuf::from(path)))
+        },
+        None => None,
+    };
+
+    let manifest = matches.value_of("manifest").map(PathBuf::from);
+    let integrity = match matches.values_of("sri") {
+        Some(values) => values
+            .map(|v| v.parse().map_err(|_| ErrorKind::InvalidParameterValue("sri").into()))
+            .collect::<Result<Vec<SriAlgorithm>>>()?,
+        None => Vec::new(),
+    };
+    let etags = matches.is_present("etag");
+    let sidecars = match matches.value_of("digests") {
+        Some("sidecar") => true,
+        Some("manifest") if manifest.is_some() => false,
+        Some(_) => return Err(ErrorKind::InvalidParameterValue("digests").into()),
+        None => manifest.is_none(),
+    } && (etags || !integrity.is_empty());
+
+    let (mut skip_exts, mut compress_exts) = match matches.value_of("blacklist") {
+        Some(path) => read_blacklist(Path::new(path))?,
+        None => (Vec::new(), Vec::new()),
+    };
+    skip_exts.extend(matches.values_of("skip-ext").into_iter().flatten().map(|e| e.to_owned()));
+    compress_exts.extend(matches.values_of("compress-ext").into_iter().flatten().map(|e| e.to_owned()));
+
+    let quality = match matches.value_of("quality") {
+        Some(q) => Some(q.parse::<u8>().map_err(|_| ErrorKind::InvalidParameterValue("quality"))?),
+        None => None
+    };
+    // Checked up front, as an out-of-range quality would otherwise fail every file
+    if quality.is_some() && !compressor.qualities().contains(&quality) {
+        return Err(ErrorKind::InvalidParameterValue("quality").into());
+    }
 
-    let parameters = Arc::new(Parameters {
+    let parameters = Parameters {
         extension: matches.value_of("ext")
             .unwrap_or(compressor.extension())
             .trim_matches(|c: char| c.is_whitespace() || c.is_control() || c == '.')
             .to_owned(),
         compressor,
-        quality: match matches.value_of("quality") {
-            Some(q) => Some(q.parse::<u8>().map_err(|_| ErrorKind::InvalidParameterValue("quality"))?),
-            None => None
+        quality,
+        case_sensitive: !matches.is_present("nocase"),
+        traversal: Traversal {
+            hidden: matches.is_present("hidden"),
+            hidden_dirs: matches.values_of_os("include-hidden-dir")
+                .map(|dirs| dirs.map(|d| d.to_owned()).collect())
+                .unwrap_or_default(),
+            ignore_files: matches.is_present("ignore-files"),
+            follow_symlinks: !matches.is_present("no-follow-symlinks"),
+            link_symlinks: matches.is_present("symlink-outputs"),
+            max_depth: match matches.value_of("max-depth") {
+                Some(depth) => Some(depth.parse::<usize>().map_err(|_| ErrorKind::InvalidParameterValue("max-depth"))?),
+                None => None,
+            },
+            one_file_system: matches.is_present("one-file-system"),
         },
-        show_summary,
         show_progress,
+        largest_first: matches.is_present("largest-first"),
+        best_of,
+        record_files: report.is_some() || manifest.is_some(),
+        hash_outputs: manifest.is_some(),
+        integrity: integrity.clone(),
+        etags,
+        dedupe: matches.is_present("dedupe"),
+        sidecars,
+        preserve_attributes: !matches.is_present("no-preserve"),
+        xattrs: match matches.values_of("xattr") {
+            Some(names) => names.map(|n| n.to_owned()).collect(),
+            None => vec!["security.selinux".to_owned()],
+        },
+        skip_exts,
+        compress_exts,
+        exclude_paths: report.iter().map(|r| r.1.clone()).chain(manifest.clone()).collect(),
+        fail_fast: matches.is_present("fail-fast"),
         threads: get_parameter(&matches, "threads", std::thread::available_parallelism()?.into())?,
-    });
+        parallel_threshold: match matches.is_present("no-parallel") {
+            true => None,
+            false => Some(get_parameter(&matches, "parallel-threshold", 16u64)?
+                .checked_mul(1024 * 1024)
+                .ok_or(ErrorKind::InvalidParameterValue("parallel-threshold"))?),
+        },
+    };
 
-    let (send_queue, stats_rx, wait_group) = start_workers(&parameters);
+    let filters = get_filters(&matches)?;
+    let result = compress_tree(parameters, &filters)?;
+    let stats = result.statistics;
 
-    let mut include_filters: Vec<String> = match matches.values_of("filters") {
-        Some(values) => Ok(values.map(|s| s.to_owned()).collect()),
-        None => Err(ErrorKind::InvalidUsage),
-    }?;
+    if let Some((format, ref path)) = report {
+        report::write_report(&stats, format, path)?;
+    }
 
-    let mut builder = GlobSetBuilder::new();
-    fix_filters(&mut include_filters);
-    for filter in include_filters.iter() {
-        let glob = GlobBuilder::new(filter)
-            .case_insensitive(!case_sensitive)
-            .literal_separator(true)
-            .build().map_err(|_| ErrorKind::InvalidIncludeFilter)?;
-        builder.add(glob);
+    if let Some(ref path) = manifest {
+        let mut existing = Manifest::load(path)?;
+        // Digests written to sidecars aren't duplicated into the manifest
+        existing.update(&stats, &integrity, etags, sidecars)?;
+        existing.prune();
+        existing.save(path)?;
     }
-    let globset = builder.build().map_err(|_| ErrorKind::InvalidIncludeFilter)?;
 
-    // Convert filters to paths and deal out conversion jobs
-    dispatch_jobs(send_queue, include_filters, globset/*, exclude_filters*/)?;
+    if show_summary {
+        println!("{}", stats);
+    }
 
-    // Wait for all jobs to finish
-    wait_group.wait();
+    for warning in stats.warnings() {
+        errstln!("Warning: {}", warning);
+    }
 
-    // Merge statistics from all threads
-    if show_summary {
-        let mut stats = Statistics::new();
-        while let Ok(thread_stats) = stats_rx.recv() {
-            stats.merge(&thread_stats);
+    if !stats.errors().is_empty() {
+        errstln!("The following files could not be compressed:");
+        for error in stats.errors() {
+            errstln!("    {}: {}", error.path.to_string_lossy(), error.error);
         }
-
-        println!("{}", stats);
+        if result.aborted {
+            errstln!("Remaining files were not processed due to --fail-fast.");
+        }
+        return Err(ErrorKind::PartialFailure(stats.errors().len()).into());
     }
 
     Ok(())
 }
 
-type ThreadParam = std::path::PathBuf;
+fn run_bench(matches: &ArgMatches) -> Result<()> {
+    let case_sensitive = !matches.is_present("nocase");
+    let samples = get_parameter(matches, "samples", 100usize)?;
+    let show_progress = !matches.is_present("no-progress");
 
-fn start_workers<'a>(params: &Arc<Parameters>) -> (chan::Sender<ThreadParam>, mpsc::Receiver<Statistics>, chan::WaitGroup) {
-    let (tx, rx) = chan::sync::<ThreadParam>(params.threads);
-    let (stats_tx, stats_rx) = std::sync::mpsc::channel::<Statistics>();
-    let wg = chan::WaitGroup::new();
+    let paths = find_files(&get_filters(matches)?, case_sensitive)?;
 
-    for _ in 0..params.threads {
-        let local_params = params.clone();
-        let local_rx = rx.clone();
-        let local_stats_tx = stats_tx.clone();
-        let local_wg = wg.clone();
-        wg.add(1);
-        std::thread::spawn(move || {
-            worker_thread(local_params, local_stats_tx, local_rx);
-            local_wg.done();
-        });
-    }
+    let benchmark = Benchmark::run(&bench::sample(paths, samples), show_progress)?;
+    println!("{}", benchmark);
 
-    (tx, stats_rx, wg)
+    Ok(())
 }
 
-fn yield_file<F>(path: PathBuf, globset: &GlobSet, callback: &F) -> Result<()>
-    where F: Fn(PathBuf) -> Result<()>
-{
-    if is_hidden(&path)? {
-        // We are ignoring .files and .directories
-        // We may add a command-line switch to control this behavior in the future
-        return Ok(());
+fn run_config(matches: &ArgMatches) -> Result<()> {
+    let server = get_parameter(matches, "server", WebServer::Nginx)?;
+    let compressors = match matches.values_of("compressor") {
+        Some(values) => values
+            .map(|v| v.parse().map_err(|_| ErrorKind::InvalidParameterValue("compressor").into()))
+            .collect::<Result<Vec<CompressionAlgorithm>>>()?,
+        None => vec![CompressionAlgorithm::GZip],
+    };
+    let extensions: Vec<&str> = matches.values_of("ext").map(|v| v.collect()).unwrap_or_default();
+    if extensions.len() > compressors.len() {
+        return Err(ErrorKind::InvalidParameterValue("extension").into());
     }
 
-    if path.is_dir() {
-        for child in path.read_dir()? {
-            let child_path = child?.path();
-            yield_file(child_path, globset, callback)?;
-        }
-    }
-    else {
-        // I'm presuming the binary search in is_blacklisted is faster
-        // than globset.is_match, but we should benchmark it at some point
-        if !is_blacklisted(&path)? && globset.is_match(&path) {
-            callback(path)?;
+    let mut variants: Vec<Variant> = Vec::with_capacity(compressors.len());
+    for (i, compressor) in compressors.iter().enumerate() {
+        let variant = Variant::new(compressor, extensions.get(i).cloned())
+            .chain_err(|| ErrorKind::InvalidParameterValue("compressor"))?;
+        // e.g. gzip and zopfli both produce .gz files
+        if !variants.iter().any(|v| v.content_encoding == variant.content_encoding && v.extension == variant.extension) {
+            variants.push(variant);
         }
     }
 
-    Ok(())
-}
-
-fn dispatch_jobs(send_queue: chan::Sender<ThreadParam>, filters: Vec<String>, globset: GlobSet/*, exclude_filters: Vec<String>*/) -> Result<()> {
-    let paths = extract_paths(&filters)?;
-    for path in paths {
-        yield_file(path, &globset, &|path: PathBuf| {
-            send_queue.send(path);
-            Ok(())
-        })?
+    match matches.is_present("lua-script") {
+        true if matches!(server, WebServer::Lighttpd) => print!("{}", generate_lighttpd_script(&variants)),
+        true => return Err(ErrorKind::InvalidParameterValue("lua-script").into()),
+        false => print!("{}", generate_config(server, &variants, matches.value_of("location").unwrap_or("/"))),
     }
-
     Ok(())
 }
 
-fn worker_thread(params: Arc<Parameters>, stats_tx: mpsc::Sender<Statistics>, rx: chan::Receiver<ThreadParam>) {
-    let mut local_stats = Statistics::new();
-
-    loop {
-        let src = match rx.recv() {
-            Some(task) => task,
-            None => break, // No more tasks
-        };
-
-        // In a nested function so we can handle errors centrally
-        fn compress_single(src: &ThreadParam, params: &Parameters, mut local_stats: &mut Statistics) -> Result<()> {
-            let dst_path = format!("{}.{}",
-                                   src.to_str().ok_or(ErrorKind::InvalidCharactersInPath)?,
-                                   params.extension);
-            let dst = Path::new(&dst_path);
-
-            // Again, in a scope for error handling
-            |local_stats: &mut Statistics| -> Result<()> {
-                    let src_metadata = std::fs::metadata(src)?;
-
-                    // Don't compress files that are already compressed that haven't changed
-                    if let Ok(dst_metadata) = std::fs::metadata(dst) {
-                        // The destination already exists
-                        let src_seconds = src_metadata.modified()?.duration_since(std::time::UNIX_EPOCH)?.as_secs();
-                        let dst_seconds = dst_metadata.modified()?.duration_since(std::time::UNIX_EPOCH)?.as_secs();
-                        match src_seconds == dst_seconds {
-                            true => {
-                                local_stats.update(src_metadata.len(), dst_metadata.len(), false);
-                                // No need to recompress
-                                return Ok(());
-                            },
-                            false => {
-                                // Return an error if we can't remove the file
-                                std::fs::remove_file(dst)?;
-                            }
-                        };
-                    }
-
-                    if params.show_progress {
-                        println!("{}", src.display());
-                    }
-                    params.compressor.compress(src.as_path(), dst, params.quality)?;
-                    let dst_metadata = std::fs::metadata(dst)?;
-                    local_stats.update(src_metadata.len(), dst_metadata.len(), true);
-                    let src_modified = filetime::FileTime::from_last_modification_time(&src_metadata);
-                    filetime::set_file_times(dst, filetime::FileTime::zero(), src_modified).unwrap_or_default();
-
-                    Ok(())
-                }(&mut local_stats)
-                .map_err(|e| {
-                    // Try deleting the invalid destination file, but don't care if we can't
-                    std::fs::remove_file(dst).unwrap_or_default();
-                    e // Bubble up the same error
-                })
+// Returns the extensions to skip and those to compress anyway, ignoring blank lines and
+// `#` comments
+fn read_blacklist(path: &Path) -> Result<(Vec<String>, Vec<String>)> {
+    let contents = std::fs::read_to_string(path)
+        .chain_err(|| format!("Error reading blacklist {}", path.display()))?;
+
+    let mut skip = Vec::new();
+    let mut compress = Vec::new();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
         }
-
-        if let Err(e) = compress_single(&src, &params, &mut local_stats) {
-            errstln!("Error compressing {}: {}", src.to_string_lossy(), e);
+        match line.strip_prefix('!') {
+            Some(ext) => compress.push(ext.to_owned()),
+            None => skip.push(line.to_owned()),
         }
     }
 
-    if !stats_tx.send(local_stats).is_ok() {
-        errstln!("Error compiling statistics!");
-    }
-}
-
-fn str_search(sorted: &[&str], search_term: &str, case_sensitive: bool) -> std::result::Result<usize, usize> {
-    use std::borrow::Cow;
-
-    let term = match case_sensitive {
-        true => Cow::from(search_term),
-        false => if search_term.chars().all(char::is_lowercase) { Cow::from(search_term) } else { Cow::from(search_term.to_lowercase()) },
-    };
-
-    sorted.binary_search_by(|probe| (*probe).cmp(term.as_ref()))
-}
-
-fn is_hidden(path: &Path) -> Result<bool> {
-    let hidden = match path.file_name() {
-        Some(x) => x.to_str().ok_or(ErrorKind::InvalidCharactersInPath)?
-            .starts_with("."),
-        None => false
-    };
-    Ok(hidden)
-}
-
-fn is_blacklisted(path: &Path) -> Result<bool> {
-    let r = match path.extension() {
-        Some(x) => {
-            let ext = x.to_str().ok_or(ErrorKind::InvalidCharactersInPath)?;
-            str_search(COMP_EXTS, &ext, false).is_ok()
-        },
-        None => false,
-    };
-
-    return Ok(r);
+    Ok((skip, compress))
 }
 
-// Prepends ./ to relative paths
-fn fix_filters(filters: &mut Vec<String>) {
-    for i in 0..filters.len() {
-        let new_path;
-        {
-            let ref path = filters[i];
-            match path.chars().next().expect("Received blank filter!") {
-                '.' | '/' => continue,
-                _ => new_path = format!("./{}", path) // Use un-prefixed path
-            }
+fn get_parameter<T>(matches: &ArgMatches, name: &'static str, default_value: T) -> Result<T>
+    where T: std::str::FromStr
+{
+    match matches.value_of(name) {
+        Some(v) => {
+            Ok(v.parse().map_err(|_| ErrorKind::InvalidParameterValue(name))?)
         }
-        filters[i] = new_path;
+        None => Ok(default_value),
     }
 }
 
-// Given a list of filters, extracts the directories that should be searched.
-// TODO: Also provide info about to what depth they should be recursed.
-use std::collections::HashSet;
-fn extract_paths(filters: &Vec<String>) -> Result<HashSet<PathBuf>> {
-    use std::iter::FromIterator;
-
-    let mut dirs = std::collections::HashSet::<PathBuf>::new();
-
-    {
-        let insert_path = &mut |filter: &String, dir: PathBuf| {
-            debug(&format!("filter {} mapped to search {}", filter, dir.display()));
-            dirs.insert(dir);
-        };
-
-        for filter in filters {
-            // Take everything until the first expression
-            let mut last_char = None::<char>;
-            let dir;
-            {
-                let partial = filter.chars().take_while(|c| match c {
-                    &'?' | &'*' | &'{' | &'[' => false,
-                    c => { last_char = Some(c.clone()); true }
-                });
-                dir = String::from_iter(partial);
-            }
-
-            let dir = match dir.chars().next() {
-                Some(c) => match c {
-                    '.' | '/' => PathBuf::from(dir),
-                    _ => {
-                        let mut pb = PathBuf::from("./");
-                        pb.push(dir);
-                        pb
-                    }
-                },
-                None => {
-                    insert_path(filter, PathBuf::from("./"));
-                    continue;
-                }
-            };
-
-            if dir.to_str().ok_or(ErrorKind::InvalidCharactersInPath)?.ends_with(filter) {
-                // The "dir" is actually a full path to a single file, return it as-is.
-                insert_path(filter, dir);
-                continue;
-            }
-
-            if last_char == Some('/') {
-                // Dir is a already a directory, return it as-is.
-                insert_path(filter, dir);
-                continue;
-            }
-
-            // We need to extract the directory from the path we have
-            let dir = match PathBuf::from(dir).parent() {
-                Some(parent) => parent.to_path_buf(),
-                None => PathBuf::from("./"),
-            };
-
-            insert_path(filter, dir);
-        }
+fn get_filters(matches: &ArgMatches) -> Result<Vec<String>> {
+    match matches.values_of("filters") {
+        Some(values) => Ok(values.map(|s| s.to_owned()).collect()),
+        None => Err(ErrorKind::InvalidUsage.into()),
     }
-
-    debug(&format!("final search paths: {:?}", dirs));
-
-    Ok(dirs)
 }
+
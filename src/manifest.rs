@@ -0,0 +1,362 @@
+// A JSON listing of every source file and its encoded variants, updated in place across runs
+
+extern crate serde_json;
+
+use digest::*;
+use errors::*;
+use lists::TEMP_SUFFIX;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use structs::*;
+
+const MANIFEST_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    // Keyed by source path
+    pub files: BTreeMap<String, SourceEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SourceEntry {
+    pub size: u64,
+    pub mtime: u64,
+    // Subresource Integrity digests, e.g. `sha384-...`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub integrity: Option<String>,
+    // Keyed by extension
+    pub variants: BTreeMap<String, VariantEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct VariantEntry {
+    pub algorithm: String,
+    pub size: u64,
+    pub sha256: String,
+    pub mtime: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Manifest {
+            version: MANIFEST_VERSION,
+            files: BTreeMap::new(),
+        }
+    }
+}
+
+impl Manifest {
+    // Loads an existing manifest, or returns an empty one if there isn't one yet
+    pub fn load(path: &Path) -> Result<Manifest> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Manifest::default()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let manifest: Manifest = serde_json::from_reader(BufReader::new(file))
+            .chain_err(|| format!("Error reading manifest {}", path.display()))?;
+        if manifest.version != MANIFEST_VERSION {
+            bail!("Unsupported manifest version {} in {}", manifest.version, path.display());
+        }
+
+        Ok(manifest)
+    }
+
+    // Merges the outcome of a run. Requires Parameters::record_files; hashes are taken from the
+    // records where available, then reused from the manifest if the file hasn't changed. With
+    // `sidecars`, SRI digests and ETags are left to the sidecar files instead.
+    pub fn update(&mut self, stats: &Statistics, integrity: &[SriAlgorithm], etags: bool, sidecars: bool) -> Result<()> {
+        let algorithms: Vec<&str> = integrity.iter().map(|a| a.name()).collect();
+        for record in stats.records() {
+            // JSON keys must be valid UTF-8, so other names are left out of the manifest
+            let source = match record.source.to_str() {
+                Some(source) => source.to_owned(),
+                None => continue,
+            };
+            let extension = match variant_extension(&record.source, &record.output) {
+                Some(extension) => extension,
+                None => continue,
+            };
+
+            match record.status {
+                FileStatus::Compressed | FileStatus::Deduplicated | FileStatus::UpToDate => {
+                    let previous_source = self.files.get(&source)
+                        .filter(|e| e.size == record.input_size && e.mtime == record.modified);
+                    let previous_integrity = previous_source.and_then(|e| e.integrity.clone());
+                    let integrity = match record.integrity {
+                        _ if sidecars => None,
+                        Some(ref integrity) => Some(integrity.clone()),
+                        None if algorithms.is_empty() => previous_integrity,
+                        None => match previous_integrity {
+                            Some(ref previous) if integrity_algorithms(previous) == algorithms => previous_integrity.clone(),
+                            _ => integrity_file(&record.source, integrity)?,
+                        },
+                    };
+
+                    let previous = self.files.get(&source).and_then(|e| e.variants.get(&extension));
+                    let unchanged = previous
+                        .filter(|v| v.size == record.output_size && v.mtime == record.modified);
+                    let sha256 = match (&record.sha256, unchanged) {
+                        (Some(sha256), _) => sha256.clone(),
+                        (None, Some(previous)) => previous.sha256.clone(),
+                        (None, None) => sha256_file(&record.output)?,
+                    };
+                    // Up-to-date records don't know which best-of candidate produced the output
+                    let algorithm = match (record.status, unchanged) {
+                        (FileStatus::UpToDate, Some(previous)) => previous.algorithm.clone(),
+                        _ => record.algorithm.to_owned(),
+                    };
+
+                    let etag = match etags {
+                        _ if sidecars => None,
+                        true => Some(etag_from_sha256(&sha256)),
+                        false => unchanged.and_then(|v| v.etag.clone()),
+                    };
+
+                    let entry = self.files.entry(source).or_insert_with(|| SourceEntry {
+                        size: 0,
+                        mtime: 0,
+                        integrity: None,
+                        variants: BTreeMap::new(),
+                    });
+                    entry.size = record.input_size;
+                    entry.mtime = record.modified;
+                    entry.integrity = integrity;
+                    entry.variants.insert(extension, VariantEntry {
+                        algorithm,
+                        size: record.output_size,
+                        sha256,
+                        mtime: record.modified,
+                        etag,
+                    });
+                },
+                FileStatus::Failed => {
+                    // The output is deleted on failure
+                    if let Some(entry) = self.files.get_mut(&source) {
+                        entry.variants.remove(&extension);
+                    }
+                },
+                // Only the output the symlink points to is listed, if it was compressed
+                FileStatus::Skipped | FileStatus::Symlinked => {},
+            }
+        }
+
+        Ok(())
+    }
+
+    // Drops entries for sources or variants that no longer exist on disk, along with variants
+    // left stale by a source that has since changed
+    pub fn prune(&mut self) {
+        self.files.retain(|source, entry| {
+            let modified = std::fs::metadata(source)
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|m| m.duration_since(std::time::UNIX_EPOCH).ok());
+            let modified = match modified {
+                Some(modified) => modified.as_secs(),
+                None => return false,
+            };
+            entry.variants.retain(|extension, variant| {
+                variant.mtime == modified && variant_path(source, extension).is_file()
+            });
+            !entry.variants.is_empty()
+        });
+    }
+
+    // Written to a temporary file first so an interrupted run can't leave a truncated manifest
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut temp_name = std::ffi::OsString::from(".");
+        temp_name.push(path.file_name().ok_or(ErrorKind::InvalidCharactersInPath)?);
+        temp_name.push(TEMP_SUFFIX);
+        let temp_path = path.with_file_name(temp_name);
+
+        let mut file = BufWriter::new(File::create(&temp_path).chain_err(|| "Error creating manifest!")?);
+        serde_json::to_writer_pretty(&mut file, self).chain_err(|| "Error writing manifest!")?;
+        file.flush().chain_err(|| "Error writing manifest!")?;
+        drop(file);
+
+        std::fs::rename(&temp_path, path).chain_err(|| "Error writing manifest!")?;
+        Ok(())
+    }
+}
+
+// The extension appended to `source` to produce `output`, e.g. `gz` or `br`
+fn variant_extension(source: &Path, output: &Path) -> Option<String> {
+    let source = source.to_str()?;
+    let output = output.to_str()?;
+    output.strip_prefix(source)
+        .and_then(|ext| ext.strip_prefix('.'))
+        .map(|ext| ext.to_owned())
+}
+
+// The algorithms of an `integrity` value, e.g. ["sha256", "sha384"]
+fn integrity_algorithms(integrity: &str) -> Vec<&str> {
+    integrity.split(' ')
+        .filter_map(|digest| digest.split('-').next())
+        .collect()
+}
+
+fn variant_path(source: &str, extension: &str) -> PathBuf {
+    PathBuf::from(format!("{}.{}", source, extension))
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use super::*;
+    use std::time::Duration;
+
+    struct Fixture {
+        dir: tempfile::TempDir,
+        mtime: u64,
+    }
+
+    impl Fixture {
+        // A source and its .gz output, sharing the source's mtime
+        fn new() -> Fixture {
+            let dir = tempfile::tempdir().unwrap();
+            std::fs::write(dir.path().join("app.js"), b"source").unwrap();
+            std::fs::write(dir.path().join("app.js.gz"), b"output").unwrap();
+            let mtime = std::fs::metadata(dir.path().join("app.js")).unwrap()
+                .modified().unwrap()
+                .duration_since(std::time::UNIX_EPOCH).unwrap()
+                .as_secs();
+            Fixture { dir, mtime }
+        }
+
+        fn source(&self) -> String {
+            self.dir.path().join("app.js").to_str().unwrap().to_owned()
+        }
+
+        fn record(&self, extension: &str, status: FileStatus, sha256: Option<&str>) -> FileRecord {
+            let source = self.dir.path().join("app.js");
+            FileRecord {
+                output: PathBuf::from(format!("{}.{}", source.display(), extension)),
+                source,
+                algorithm: "gzip",
+                input_size: 6,
+                output_size: 6,
+                duration: Duration::from_secs(0),
+                status,
+                modified: self.mtime,
+                sha256: sha256.map(|s| s.to_owned()),
+                integrity: None,
+            }
+        }
+    }
+
+    fn stats(records: Vec<FileRecord>) -> Statistics {
+        let mut stats = Statistics::new();
+        for record in records {
+            stats.record_file(record);
+        }
+        stats
+    }
+
+    #[test]
+    fn merges_variants_across_runs() {
+        let fixture = Fixture::new();
+        let mut manifest = Manifest::default();
+        manifest.update(&stats(vec![fixture.record("gz", FileStatus::Compressed, Some("aa"))]), &[], true, false).unwrap();
+        manifest.update(&stats(vec![fixture.record("br", FileStatus::Compressed, Some("bb"))]), &[], false, false).unwrap();
+
+        let entry = &manifest.files[&fixture.source()];
+        assert_eq!(entry.variants.keys().collect::<Vec<_>>(), vec!["br", "gz"]);
+        assert_eq!(entry.variants["gz"].etag, Some(etag_from_sha256("aa")));
+        assert_eq!(entry.variants["br"].etag, None);
+    }
+
+    #[test]
+    fn digests_are_left_to_sidecars() {
+        let fixture = Fixture::new();
+        let mut record = fixture.record("gz", FileStatus::Compressed, Some("aa"));
+        record.integrity = Some("sha384-abc".to_owned());
+        let records = stats(vec![record]);
+
+        let mut manifest = Manifest::default();
+        manifest.update(&records, &[SriAlgorithm::Sha384], true, false).unwrap();
+        let entry = &manifest.files[&fixture.source()];
+        assert_eq!(entry.integrity.as_deref(), Some("sha384-abc"));
+        assert_eq!(entry.variants["gz"].etag, Some(etag_from_sha256("aa")));
+
+        let mut manifest = Manifest::default();
+        manifest.update(&records, &[SriAlgorithm::Sha384], true, true).unwrap();
+        let entry = &manifest.files[&fixture.source()];
+        assert_eq!(entry.integrity, None);
+        assert_eq!(entry.variants["gz"].etag, None);
+        assert_eq!(entry.variants["gz"].sha256, "aa");
+    }
+
+    #[test]
+    fn reuses_hashes_of_unchanged_outputs() {
+        let fixture = Fixture::new();
+        let mut manifest = Manifest::default();
+        manifest.update(&stats(vec![fixture.record("gz", FileStatus::Compressed, Some("aa"))]), &[], false, false).unwrap();
+        // Up-to-date outputs aren't hashed, and would otherwise be read from disk
+        manifest.update(&stats(vec![fixture.record("gz", FileStatus::UpToDate, None)]), &[], false, false).unwrap();
+
+        assert_eq!(manifest.files[&fixture.source()].variants["gz"].sha256, "aa");
+    }
+
+    #[test]
+    fn failed_outputs_are_removed() {
+        let fixture = Fixture::new();
+        let mut manifest = Manifest::default();
+        manifest.update(&stats(vec![
+            fixture.record("gz", FileStatus::Compressed, Some("aa")),
+            fixture.record("br", FileStatus::Compressed, Some("bb")),
+        ]), &[], false, false).unwrap();
+        manifest.update(&stats(vec![
+            fixture.record("br", FileStatus::Failed, None),
+            fixture.record("zst", FileStatus::Skipped, None),
+        ]), &[], false, false).unwrap();
+
+        assert_eq!(manifest.files[&fixture.source()].variants.keys().collect::<Vec<_>>(), vec!["gz"]);
+    }
+
+    #[test]
+    fn prunes_missing_and_stale_entries() {
+        let fixture = Fixture::new();
+        let mut manifest = Manifest::default();
+        manifest.update(&stats(vec![
+            fixture.record("gz", FileStatus::Compressed, Some("aa")),
+            // No app.js.br exists on disk
+            fixture.record("br", FileStatus::Compressed, Some("bb")),
+        ]), &[], false, false).unwrap();
+        // Compressed from an older version of the source
+        let mut stale = fixture.record("zst", FileStatus::Compressed, Some("cc"));
+        stale.modified -= 10;
+        std::fs::write(&stale.output, b"output").unwrap();
+        manifest.update(&stats(vec![stale]), &[], false, false).unwrap();
+
+        manifest.prune();
+        assert_eq!(manifest.files[&fixture.source()].variants.keys().collect::<Vec<_>>(), vec!["gz"]);
+
+        std::fs::remove_file(fixture.dir.path().join("app.js")).unwrap();
+        manifest.prune();
+        assert!(manifest.files.is_empty());
+    }
+
+    #[test]
+    fn saves_and_loads() {
+        let fixture = Fixture::new();
+        let path = fixture.dir.path().join("manifest.json");
+        assert!(Manifest::load(&path).unwrap().files.is_empty());
+
+        let mut manifest = Manifest::default();
+        manifest.update(&stats(vec![fixture.record("gz", FileStatus::Compressed, Some("aa"))]), &[], false, false).unwrap();
+        manifest.save(&path).unwrap();
+        let loaded = Manifest::load(&path).unwrap();
+        assert_eq!(loaded.files[&fixture.source()].variants["gz"].sha256, "aa");
+
+        std::fs::write(&path, br#"{"version": 99, "files": {}}"#).unwrap();
+        assert!(Manifest::load(&path).is_err());
+    }
+}
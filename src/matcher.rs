@@ -0,0 +1,157 @@
+// The compiled include filters, which can also tell whether a directory could contain any
+// matches at all so that the rest of the tree is never opened.
+
+use errors::*;
+use globset::{GlobBuilder, GlobMatcher, GlobSet, GlobSetBuilder};
+use std::path::Path;
+
+pub struct Matcher {
+    files: GlobSet,
+    // For each filter, a matcher per path component, or None if it can match below any directory
+    dirs: Vec<Option<Vec<Segment>>>,
+}
+
+enum Segment {
+    Pattern(GlobMatcher),
+    // `**`, which matches any number of directories
+    Recursive,
+}
+
+impl Matcher {
+    // Expects the filters to have been through fix_filters()
+    pub fn new(filters: &[String], case_sensitive: bool) -> Result<Matcher> {
+        let glob = |pattern: &str, literal_separator: bool| {
+            GlobBuilder::new(pattern)
+                .case_insensitive(!case_sensitive)
+                .literal_separator(literal_separator)
+                .build()
+                .map_err(|_| Error::from(ErrorKind::InvalidIncludeFilter))
+        };
+
+        let mut builder = GlobSetBuilder::new();
+        let mut dirs = Vec::with_capacity(filters.len());
+        for filter in filters {
+            builder.add(glob(filter, true)?);
+
+            // Alternatives spanning several components can't be matched one component at a time
+            if has_separator_in_braces(filter) {
+                dirs.push(None);
+                continue;
+            }
+
+            let mut segments = Vec::new();
+            for component in Path::new(filter).components() {
+                let component = component.as_os_str().to_str().ok_or(ErrorKind::InvalidIncludeFilter)?;
+                segments.push(match component.contains("**") {
+                    true => Segment::Recursive,
+                    false => Segment::Pattern(glob(component, false)?.compile_matcher()),
+                });
+            }
+            dirs.push(Some(segments));
+        }
+
+        Ok(Matcher {
+            files: builder.build().map_err(|_| ErrorKind::InvalidIncludeFilter)?,
+            dirs,
+        })
+    }
+
+    pub fn is_match(&self, path: &Path) -> bool {
+        self.files.is_match(path)
+    }
+
+    // Whether any filter could match a file somewhere below `dir`
+    pub fn may_match_within(&self, dir: &Path) -> bool {
+        self.dirs.iter().any(|filter| match *filter {
+            Some(ref segments) => prefix_matches(segments, dir),
+            None => true,
+        })
+    }
+}
+
+fn prefix_matches(segments: &[Segment], dir: &Path) -> bool {
+    let mut segments = segments.iter();
+    for component in dir.components() {
+        match segments.next() {
+            Some(Segment::Recursive) => return true,
+            Some(Segment::Pattern(glob)) => if !glob.is_match(component.as_os_str()) {
+                return false;
+            },
+            // The filter ends above this directory
+            None => return false,
+        }
+    }
+
+    // A file within `dir` needs at least one more component
+    segments.next().is_some()
+}
+
+fn has_separator_in_braces(filter: &str) -> bool {
+    let mut depth = 0;
+    for c in filter.chars() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            '/' if depth > 0 => return true,
+            _ => {},
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(filters: &[&str]) -> Matcher {
+        let filters: Vec<String> = filters.iter().map(|f| f.to_string()).collect();
+        Matcher::new(&filters, true).unwrap()
+    }
+
+    #[test]
+    fn matches_files() {
+        let m = matcher(&["./public/*.js", "./assets/**/*.css"]);
+        assert!(m.is_match(Path::new("./public/app.js")));
+        assert!(!m.is_match(Path::new("./public/lib/app.js")));
+        assert!(m.is_match(Path::new("./assets/site.css")));
+        assert!(m.is_match(Path::new("./assets/a/b/site.css")));
+        assert!(!m.is_match(Path::new("./assets/site.js")));
+    }
+
+    #[test]
+    fn case_insensitive() {
+        let filters = vec!["./*.JS".to_owned()];
+        assert!(Matcher::new(&filters, false).unwrap().is_match(Path::new("./app.js")));
+        assert!(!Matcher::new(&filters, true).unwrap().is_match(Path::new("./app.js")));
+    }
+
+    #[test]
+    fn prunes_directories() {
+        let m = matcher(&["./public/*/*.js"]);
+        assert!(m.may_match_within(Path::new("./public")));
+        assert!(m.may_match_within(Path::new("./public/js")));
+        // Files within it would be too deep
+        assert!(!m.may_match_within(Path::new("./public/js/vendor")));
+        assert!(!m.may_match_within(Path::new("./private")));
+    }
+
+    #[test]
+    fn recursive_filters_match_below_any_depth() {
+        let m = matcher(&["./src/**/*.rs"]);
+        assert!(m.may_match_within(Path::new("./src/a/b/c")));
+        assert!(!m.may_match_within(Path::new("./target")));
+    }
+
+    #[test]
+    fn braces_spanning_components_are_never_pruned() {
+        let m = matcher(&["./{a/b,c}/*.js"]);
+        assert!(m.may_match_within(Path::new("./d/e/f")));
+        assert!(m.is_match(Path::new("./a/b/x.js")));
+        assert!(m.is_match(Path::new("./c/x.js")));
+    }
+
+    #[test]
+    fn rejects_invalid_filters() {
+        assert!(Matcher::new(&["./[".to_owned()], true).is_err());
+    }
+}
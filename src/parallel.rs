@@ -0,0 +1,347 @@
+// Splits the compression of a single large file across workers that have run out of files
+
+extern crate flate2;
+
+use compression;
+use digest::*;
+use errors::*;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use structs::*;
+
+// Each block is deflated independently, so smaller blocks cost compression ratio
+const BLOCK_SIZE: usize = 1024 * 1024;
+
+/// Workers from `start_workers()` that have drained the queue and can be lent out.
+pub struct Helpers {
+    state: Mutex<HelperState>,
+    cvar: Condvar,
+}
+
+struct HelperState {
+    jobs: Vec<Arc<BlockJob>>,
+    // Workers still taking files from the queue
+    busy: usize,
+    // Retired workers that are neither helping with a block job nor lent to zstd
+    idle: usize,
+}
+
+impl Helpers {
+    pub fn new(workers: usize) -> Helpers {
+        Helpers {
+            state: Mutex::new(HelperState {
+                jobs: Vec::new(),
+                busy: workers,
+                idle: 0,
+            }),
+            cvar: Condvar::new(),
+        }
+    }
+
+    // Called by a worker once the queue is drained. Helps with block jobs until no worker is
+    // left that could start another one.
+    pub fn retire(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.busy -= 1;
+        state.idle += 1;
+        self.cvar.notify_all();
+
+        loop {
+            if state.idle > 0 {
+                if let Some(job) = state.jobs.iter().find(|j| j.has_work()).cloned() {
+                    state.idle -= 1;
+                    drop(state);
+                    job.work(true);
+                    state = self.state.lock().unwrap();
+                    state.idle += 1;
+                    continue;
+                }
+            }
+
+            if state.busy == 0 {
+                break;
+            }
+            state = self.cvar.wait(state).unwrap();
+        }
+    }
+
+    // Reserves up to `max` idle workers, which stay parked until returned. They do no work
+    // themselves: they only make room for threads started elsewhere, such as libzstd's.
+    fn borrow(&self, max: usize) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let borrowed = state.idle.min(max);
+        state.idle -= borrowed;
+        borrowed
+    }
+
+    fn give_back(&self, count: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.idle += count;
+        self.cvar.notify_all();
+    }
+
+    fn register(&self, job: &Arc<BlockJob>) {
+        self.state.lock().unwrap().jobs.push(job.clone());
+        self.cvar.notify_all();
+    }
+
+    fn unregister(&self, job: &Arc<BlockJob>) {
+        self.state.lock().unwrap().jobs.retain(|j| !Arc::ptr_eq(j, job));
+    }
+}
+
+type DeflatedBlock = std::result::Result<Vec<u8>, flate2::CompressError>;
+
+// Deflates fixed-size blocks of an in-memory input, each on whichever thread claims it first
+struct BlockJob {
+    data: Vec<u8>,
+    level: flate2::Compression,
+    block_count: usize,
+    next: AtomicUsize,
+    // Whether a retired worker deflated any of the blocks
+    helped: AtomicBool,
+    blocks: Mutex<Vec<Option<DeflatedBlock>>>,
+    finished: Condvar,
+}
+
+impl BlockJob {
+    fn new(data: Vec<u8>, level: flate2::Compression) -> BlockJob {
+        let block_count = std::cmp::max(1, data.len().div_ceil(BLOCK_SIZE));
+        BlockJob {
+            data,
+            level,
+            block_count,
+            next: AtomicUsize::new(0),
+            helped: AtomicBool::new(false),
+            blocks: Mutex::new((0..block_count).map(|_| None).collect()),
+            finished: Condvar::new(),
+        }
+    }
+
+    fn has_work(&self) -> bool {
+        self.next.load(Ordering::SeqCst) < self.block_count
+    }
+
+    fn work(&self, helping: bool) {
+        loop {
+            let i = self.next.fetch_add(1, Ordering::SeqCst);
+            if i >= self.block_count {
+                break;
+            }
+            if helping {
+                self.helped.store(true, Ordering::SeqCst);
+            }
+
+            let end = std::cmp::min(self.data.len(), (i + 1) * BLOCK_SIZE);
+            let block = deflate_block(&self.data[i * BLOCK_SIZE..end], self.level, i + 1 == self.block_count);
+            self.blocks.lock().unwrap()[i] = Some(block);
+            self.finished.notify_all();
+        }
+    }
+
+    fn wait(&self) -> Result<Vec<Vec<u8>>> {
+        let mut blocks = self.blocks.lock().unwrap();
+        while blocks.iter().any(|b| b.is_none()) {
+            blocks = self.finished.wait(blocks).unwrap();
+        }
+
+        blocks.drain(..)
+            .map(|b| b.expect("All blocks are finished").chain_err(|| "Fatal gzip encoder error!"))
+            .collect()
+    }
+}
+
+// All but the last block end in a sync flush rather than a final block, so that the raw
+// deflate streams can be concatenated into one.
+fn deflate_block(data: &[u8], level: flate2::Compression, last: bool) -> DeflatedBlock {
+    let mut compressor = flate2::Compress::new(level, false);
+    let flush = match last {
+        true => flate2::FlushCompress::Finish,
+        false => flate2::FlushCompress::Sync,
+    };
+
+    let mut output = Vec::with_capacity(data.len() / 2 + 1024);
+    loop {
+        if output.capacity() - output.len() < 1024 {
+            output.reserve(data.len() / 4 + 1024);
+        }
+        let status = compressor.compress_vec(&data[compressor.total_in() as usize..], &mut output, flush)?;
+        let consumed = compressor.total_in() as usize == data.len();
+        match status {
+            flate2::Status::StreamEnd => break,
+            // A flush is complete once it no longer fills the output buffer
+            _ if !last && consumed && output.len() < output.capacity() => break,
+            _ => {},
+        }
+    }
+
+    Ok(output)
+}
+
+// Whether compress_large() can split the work for this algorithm
+pub fn supports(algorithm: CompressionAlgorithm) -> bool {
+    matches!(algorithm, CompressionAlgorithm::GZip | CompressionAlgorithm::Zstd)
+}
+
+// Compresses `src` with the help of idle workers, digesting the source and the output. Also
+// returns whether the work was actually split, as no worker may have been free to help.
+pub fn compress_large(algorithm: CompressionAlgorithm, src_path: &Path, dst_path: &Path, quality: Option<u8>,
+                      integrity: &[SriAlgorithm], helpers: &Helpers) -> Result<(Digests, bool)> {
+    let mut src = HashingReader::new(File::open(src_path)?, integrity);
+    let mut dst = HashingWriter::new(BufWriter::new(File::create(dst_path)?));
+
+    let split = match algorithm {
+        CompressionAlgorithm::GZip => {
+            let mut data = Vec::new();
+            src.read_to_end(&mut data).chain_err(|| "Error reading from source file!")?;
+            let mut crc = flate2::Crc::new();
+            crc.update(&data);
+            let size = data.len() as u32;
+
+            // Blocks are handed to retired workers as they become available
+            let job = Arc::new(BlockJob::new(data, compression::gzip_level(quality)?));
+            helpers.register(&job);
+            job.work(false);
+            let blocks = job.wait();
+            helpers.unregister(&job);
+            let helped = job.helped.load(Ordering::SeqCst);
+
+            // A minimal gzip header: no file name or mtime, unknown OS
+            dst.write_all(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff])?;
+            for block in blocks? {
+                dst.write_all(&block)?;
+            }
+            dst.write_all(&crc.sum().to_le_bytes())?;
+            dst.write_all(&size.to_le_bytes())?;
+            helped
+        },
+        CompressionAlgorithm::Zstd => {
+            // zstd can't add threads mid-stream, so it only gets the workers idle right now. Those
+            // stay parked while libzstd runs one thread for each of them and one for this worker,
+            // which only feeds it input, so that no more threads than configured are busy.
+            let borrowed = helpers.borrow(usize::MAX);
+            let workers = match borrowed {
+                0 => 0,
+                n => n as u32 + 1,
+            };
+            let result = compression::zstd_encode(&mut src, &mut dst, quality, workers);
+            helpers.give_back(borrowed);
+            result?;
+            borrowed > 0
+        },
+        _ => bail!("{} does not support parallel compression", algorithm),
+    };
+
+    dst.flush().chain_err(|| "Error writing to destination file!")?;
+    let digests = Digests {
+        integrity: src.integrity(),
+        sha256: dst.sha256(),
+    };
+    Ok((digests, split))
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+    extern crate zstd;
+
+    use super::*;
+    use self::flate2::read::GzDecoder;
+
+    // Compressible, but not so repetitive that every block is trivial
+    fn sample(len: usize) -> Vec<u8> {
+        (0..len).map(|i| ((i * 31) ^ (i >> 9)) as u8 % 64 + b' ').collect()
+    }
+
+    // Compresses `data` on this thread with `helpers` retired workers lending a hand
+    fn round_trip(algorithm: CompressionAlgorithm, data: &[u8], helpers: usize) -> (Vec<u8>, Vec<u8>, Digests, bool) {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("large.bin");
+        let dst = dir.path().join("large.bin.out");
+        std::fs::write(&src, data).unwrap();
+
+        let pool = Helpers::new(helpers + 1);
+        let (digests, split) = std::thread::scope(|scope| {
+            for _ in 0..helpers {
+                scope.spawn(|| pool.retire());
+            }
+            // So that they can all be borrowed
+            while pool.state.lock().unwrap().idle < helpers {
+                std::thread::yield_now();
+            }
+            let result = compress_large(algorithm, &src, &dst, None, &[SriAlgorithm::Sha384], &pool).unwrap();
+            pool.retire();
+            result
+        });
+
+        let output = std::fs::read(&dst).unwrap();
+        let decoded = match algorithm {
+            CompressionAlgorithm::GZip => {
+                let mut decoded = Vec::new();
+                GzDecoder::new(&output[..]).read_to_end(&mut decoded).unwrap();
+                decoded
+            },
+            _ => zstd::stream::decode_all(&output[..]).unwrap(),
+        };
+        (output, decoded, digests, split)
+    }
+
+    #[test]
+    fn gzip_blocks_join_into_one_stream() {
+        let data = sample(3 * BLOCK_SIZE + 12345);
+        for helpers in 0..3 {
+            let (output, decoded, digests, split) = round_trip(CompressionAlgorithm::GZip, &data, helpers);
+            assert!(decoded == data, "Corrupt output with {} helpers", helpers);
+            // Whether a helper got to a block first is up to the scheduler
+            assert!(helpers > 0 || !split);
+            assert!(output.len() < data.len());
+            assert_eq!(digests.sha256, sha256_of(&output));
+            assert_eq!(digests.integrity, integrity_of(&data));
+        }
+    }
+
+    #[test]
+    fn gzip_handles_empty_and_partial_blocks() {
+        for &len in [0, 1, BLOCK_SIZE - 1, BLOCK_SIZE, BLOCK_SIZE + 1].iter() {
+            let data = sample(len);
+            let (_, decoded, _, _) = round_trip(CompressionAlgorithm::GZip, &data, 1);
+            assert!(decoded == data, "Corrupt output for {} bytes", len);
+        }
+    }
+
+    #[test]
+    fn zstd_round_trips() {
+        let data = sample(2 * BLOCK_SIZE);
+        for helpers in 0..3 {
+            let (_, decoded, digests, split) = round_trip(CompressionAlgorithm::Zstd, &data, helpers);
+            assert!(decoded == data, "Corrupt output with {} helpers", helpers);
+            assert_eq!(split, helpers > 0);
+            assert_eq!(digests.integrity, integrity_of(&data));
+        }
+    }
+
+    #[test]
+    fn unsupported_algorithms_fail() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("a");
+        std::fs::write(&src, b"a").unwrap();
+        let pool = Helpers::new(1);
+        assert!(!supports(CompressionAlgorithm::Brotli));
+        assert!(compress_large(CompressionAlgorithm::Brotli, &src, &dir.path().join("a.br"), None, &[], &pool).is_err());
+    }
+
+    fn sha256_of(data: &[u8]) -> String {
+        let mut writer = HashingWriter::new(std::io::sink());
+        writer.write_all(data).unwrap();
+        writer.sha256()
+    }
+
+    fn integrity_of(data: &[u8]) -> Option<String> {
+        let mut reader = HashingReader::new(data, &[SriAlgorithm::Sha384]);
+        std::io::copy(&mut reader, &mut std::io::sink()).unwrap();
+        reader.integrity()
+    }
+}
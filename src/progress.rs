@@ -0,0 +1,122 @@
+extern crate indicatif;
+
+use self::indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::io::IsTerminal;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Live progress display when stdout is a terminal, line-per-file output otherwise
+pub struct Progress {
+    enabled: bool,
+    bars: Option<Bars>,
+    files_done: AtomicU64,
+    files_total: AtomicU64,
+}
+
+struct Bars {
+    multi: MultiProgress,
+    overall: ProgressBar,
+    workers: Vec<ProgressBar>,
+}
+
+impl Progress {
+    pub fn new(threads: usize, enabled: bool) -> Progress {
+        let bars = match enabled && std::io::stdout().is_terminal() {
+            true => Some(Bars::new(threads)),
+            false => None,
+        };
+
+        Progress {
+            enabled,
+            bars,
+            files_done: AtomicU64::new(0),
+            files_total: AtomicU64::new(0),
+        }
+    }
+
+    // Only the live display needs the sizes of the files to be compressed
+    pub fn is_interactive(&self) -> bool {
+        self.bars.is_some()
+    }
+
+    pub fn add_to_total(&self, files: u64, bytes: u64) {
+        self.files_total.fetch_add(files, Ordering::Relaxed);
+        if let Some(ref bars) = self.bars {
+            bars.overall.inc_length(bytes);
+            self.update_message(bars);
+        }
+    }
+
+    pub fn file_started(&self, worker: usize, path: &Path) {
+        if let Some(ref bars) = self.bars {
+            bars.workers[worker].set_message(path.display().to_string());
+        }
+    }
+
+    // `label` is printed next to the path in line-per-file mode, if present
+    pub fn file_finished(&self, worker: usize, path: &Path, bytes: u64, compressed: bool, label: Option<&str>) {
+        self.files_done.fetch_add(1, Ordering::Relaxed);
+        match self.bars {
+            Some(ref bars) => {
+                bars.workers[worker].set_message("");
+                bars.overall.inc(bytes);
+                self.update_message(bars);
+            },
+            None if compressed && self.enabled => match label {
+                Some(label) => println!("{} [{}]", path.display(), label),
+                None => println!("{}", path.display()),
+            },
+            None => {},
+        }
+    }
+
+    // Prints a message to stderr without clobbering the live display. Nothing is printed with
+    // progress disabled, e.g. for library callers, who find the errors in the statistics.
+    pub fn error(&self, message: &str) {
+        match self.bars {
+            Some(ref bars) => bars.multi.suspend(|| errstln!("{}", message)),
+            None if self.enabled => errstln!("{}", message),
+            None => {},
+        }
+    }
+
+    pub fn finish(&self) {
+        if let Some(ref bars) = self.bars {
+            for bar in bars.workers.iter() {
+                bar.finish_and_clear();
+            }
+            bars.overall.finish_and_clear();
+        }
+    }
+
+    fn update_message(&self, bars: &Bars) {
+        bars.overall.set_message(format!("{}/{} files",
+                                         self.files_done.load(Ordering::Relaxed),
+                                         self.files_total.load(Ordering::Relaxed)));
+    }
+}
+
+impl Bars {
+    fn new(threads: usize) -> Bars {
+        let multi = MultiProgress::with_draw_target(ProgressDrawTarget::stdout());
+        let overall = multi.add(ProgressBar::new(0));
+        overall.set_style(ProgressStyle::with_template(
+                "{wide_bar} {bytes}/{total_bytes} {msg} ({binary_bytes_per_sec}, ETA {eta})")
+            .expect("Invalid progress bar template!"));
+
+        let worker_style = ProgressStyle::with_template("  [{prefix}] {wide_msg}")
+            .expect("Invalid progress bar template!");
+        let workers = (0..threads).map(|i| {
+            let bar = multi.add(ProgressBar::new_spinner());
+            bar.set_style(worker_style.clone());
+            bar.set_prefix((i + 1).to_string());
+            bar
+        }).collect();
+
+        Bars {
+            multi,
+            overall,
+            workers,
+        }
+    }
+}
@@ -0,0 +1,188 @@
+extern crate csv;
+extern crate serde_json;
+
+use errors::*;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use structs::*;
+
+const CSV_HEADERS: &[&str] = &["source", "output", "algorithm", "input_size", "output_size", "duration", "status"];
+
+#[derive(Serialize)]
+struct JsonReport<'a> {
+    summary: &'a Statistics,
+    savings: f32,
+    savings_now: f32,
+    // The total of the per-file durations, in seconds
+    duration: f64,
+    files: &'a [FileRecord],
+}
+
+// Follows the per-file records in CSV reports, with the same totals (and names) as the JSON
+// report's summary
+#[derive(Serialize)]
+struct CsvSummary {
+    total_file_count: u32,
+    total_file_count_now: u32,
+    total_skipped: u32,
+    total_symlinked: u32,
+    total_deduplicated: u32,
+    total_dedupe_copies: u32,
+    errors: usize,
+    total_uncompressed: u64,
+    total_uncompressed_now: u64,
+    total_compressed: u64,
+    total_compressed_now: u64,
+    dedupe_bytes_saved: u64,
+    dedupe_time_saved: f64,
+    savings: f32,
+    savings_now: f32,
+    duration: f64,
+}
+
+pub fn write_report(stats: &Statistics, format: ReportFormat, path: &Path) -> Result<()> {
+    let mut file = BufWriter::new(File::create(path).chain_err(|| "Error creating report file!")?);
+    let duration = stats.records().iter().map(|r| r.duration.as_secs_f64()).sum();
+
+    match format {
+        ReportFormat::Json => {
+            let report = JsonReport {
+                summary: stats,
+                savings: 1f32 - stats.savings_ratio(),
+                savings_now: 1f32 - stats.savings_ratio_now(),
+                duration,
+                files: stats.records(),
+            };
+            serde_json::to_writer_pretty(file, &report).chain_err(|| "Error writing JSON report!")?;
+        },
+        ReportFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(file);
+            if stats.records().is_empty() {
+                // Headers are otherwise derived from the first record
+                writer.write_record(CSV_HEADERS).chain_err(|| "Error writing CSV report!")?;
+            }
+            for record in stats.records() {
+                writer.serialize(record).chain_err(|| "Error writing CSV report!")?;
+            }
+            file = writer.into_inner().chain_err(|| "Error writing CSV report!")?;
+
+            // Separated by a blank line, with its own headers
+            file.write_all(b"\n")?;
+            let mut writer = csv::Writer::from_writer(file);
+            writer.serialize(CsvSummary {
+                total_file_count: stats.file_count(),
+                total_file_count_now: stats.file_count_now(),
+                total_skipped: stats.skipped(),
+                total_symlinked: stats.symlinked(),
+                total_deduplicated: stats.deduplicated(),
+                total_dedupe_copies: stats.dedupe_copies(),
+                errors: stats.errors().len(),
+                total_uncompressed: stats.uncompressed_size(),
+                total_uncompressed_now: stats.uncompressed_size_now(),
+                total_compressed: stats.compressed_size(),
+                total_compressed_now: stats.compressed_size_now(),
+                dedupe_bytes_saved: stats.dedupe_bytes_saved(),
+                dedupe_time_saved: stats.dedupe_time_saved().as_secs_f64(),
+                savings: 1f32 - stats.savings_ratio(),
+                savings_now: 1f32 - stats.savings_ratio_now(),
+                duration,
+            }).chain_err(|| "Error writing CSV report!")?;
+            writer.flush()?;
+        },
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use self::serde_json::Value;
+    use super::*;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    fn record(source: &str, size: u64, output_size: u64, millis: u64, status: FileStatus) -> FileRecord {
+        FileRecord {
+            source: PathBuf::from(source),
+            output: PathBuf::from(format!("{}.gz", source)),
+            algorithm: "gzip",
+            input_size: size,
+            output_size,
+            duration: Duration::from_millis(millis),
+            status,
+            modified: 0,
+            sha256: None,
+            integrity: None,
+        }
+    }
+
+    fn statistics() -> Statistics {
+        let mut stats = Statistics::new();
+        stats.update(1000, 250, true);
+        stats.record_file(record("a.js", 1000, 250, 500, FileStatus::Compressed));
+        stats.update(1000, 500, false);
+        stats.record_file(record("b.css", 1000, 500, 0, FileStatus::UpToDate));
+        stats.record_skip();
+        stats.record_file(record("c.png", 10, 10, 0, FileStatus::Skipped));
+        stats.record_error(PathBuf::from("d.svg"), &Error::from("boom"));
+        stats.record_file(record("d.svg", 10, 0, 0, FileStatus::Failed));
+        stats
+    }
+
+    #[test]
+    fn csv_lists_files_then_the_totals() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("report.csv");
+        write_report(&statistics(), ReportFormat::Csv, &path).unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines[0], CSV_HEADERS.join(","));
+        assert_eq!(lines[1], "a.js,a.js.gz,gzip,1000,250,0.5,compressed");
+        assert_eq!(lines[2], "b.css,b.css.gz,gzip,1000,500,0.0,up-to-date");
+        assert_eq!(lines[3], "c.png,c.png.gz,gzip,10,10,0.0,skipped");
+        assert_eq!(lines[4], "d.svg,d.svg.gz,gzip,10,0,0.0,failed");
+        assert_eq!(lines[5], "");
+
+        let names: Vec<&str> = lines[6].split(',').collect();
+        let values: Vec<&str> = lines[7].split(',').collect();
+        assert_eq!(lines.len(), 8);
+        let total = |name: &str| values[names.iter().position(|&n| n == name).unwrap()];
+        assert_eq!(total("total_file_count"), "2");
+        assert_eq!(total("total_file_count_now"), "1");
+        assert_eq!(total("total_skipped"), "1");
+        assert_eq!(total("errors"), "1");
+        assert_eq!(total("total_uncompressed"), "2000");
+        assert_eq!(total("total_compressed"), "750");
+        assert_eq!(total("savings"), "0.625");
+        assert_eq!(total("savings_now"), "0.75");
+        assert_eq!(total("duration"), "0.5");
+    }
+
+    #[test]
+    fn csv_and_json_report_the_same_totals() {
+        let dir = tempfile::tempdir().unwrap();
+        let stats = statistics();
+        write_report(&stats, ReportFormat::Csv, &dir.path().join("report.csv")).unwrap();
+        write_report(&stats, ReportFormat::Json, &dir.path().join("report.json")).unwrap();
+
+        let json: Value = serde_json::from_slice(&std::fs::read(dir.path().join("report.json")).unwrap()).unwrap();
+        let csv = std::fs::read_to_string(dir.path().join("report.csv")).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        let names = lines[lines.len() - 2].split(',');
+        let values = lines[lines.len() - 1].split(',');
+        for (name, value) in names.zip(values) {
+            let expected = match json["summary"].get(name).or_else(|| json.get(name)) {
+                Some(Value::Array(errors)) => errors.len().to_string(),
+                Some(other) => other.to_string(),
+                None => panic!("{} is missing from the JSON report", name),
+            };
+            assert_eq!(value, expected, "{}", name);
+        }
+        assert_eq!(json["files"].as_array().unwrap().len(), 4);
+        assert_eq!(json["files"][0]["status"], "compressed");
+    }
+}
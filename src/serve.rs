@@ -0,0 +1,307 @@
+// A minimal static file server for checking that a precompressed tree is served correctly
+
+extern crate tiny_http;
+
+use self::tiny_http::{Header, Method, Request, Response, StatusCode};
+use embed::{content_type, etag};
+use errors::*;
+use std::path::{Component, Path, PathBuf};
+use structs::*;
+
+pub struct Server {
+    root: PathBuf,
+    // (content-encoding, extension) pairs in order of server preference
+    encodings: Vec<(&'static str, &'static str)>,
+}
+
+impl Server {
+    pub fn new(root: &Path) -> Server {
+        let mut encodings = Vec::new();
+        for format in [CompressionAlgorithm::Brotli, CompressionAlgorithm::Zstd, CompressionAlgorithm::GZip].iter() {
+            if let Some(encoding) = format.content_encoding() {
+                if !encodings.iter().any(|&(e, _)| e == encoding) {
+                    encodings.push((encoding, format.extension()));
+                }
+            }
+        }
+
+        Server {
+            root: root.to_owned(),
+            encodings,
+        }
+    }
+
+    pub fn run(&self, address: &str) -> Result<()> {
+        let server = tiny_http::Server::http(address)
+            .map_err(|e| format!("Error listening on {}: {}", address, e))?;
+        println!("Serving {} on http://{}/", self.root.display(), server.server_addr());
+
+        for request in server.incoming_requests() {
+            if let Err(e) = self.respond(request) {
+                errstln!("{}", e);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn respond(&self, request: Request) -> Result<()> {
+        if *request.method() != Method::Get && *request.method() != Method::Head {
+            println!("{} {} -> 405", request.method(), request.url());
+            request.respond(Response::empty(405))?;
+            return Ok(());
+        }
+
+        let path = match self.resolve(request.url()) {
+            Some(path) => path,
+            None => {
+                println!("{} {} -> 404", request.method(), request.url());
+                request.respond(Response::empty(404))?;
+                return Ok(());
+            }
+        };
+
+        let accepted = request.headers().iter()
+            .find(|h| h.field.equiv("Accept-Encoding"))
+            .map(|h| parse_accept_encoding(h.value.as_str()))
+            .unwrap_or_default();
+
+        let (encoding, served) = match self.choose_variant(&path, &accepted) {
+            Some(choice) => choice,
+            None => {
+                println!("{} {} -> 406", request.method(), request.url());
+                request.respond(Response::empty(406))?;
+                return Ok(());
+            }
+        };
+
+        let data = std::fs::read(&served)?;
+        let tag = etag(&data);
+        let not_modified = request.headers().iter()
+            .find(|h| h.field.equiv("If-None-Match"))
+            .map(|h| h.value.as_str().split(',').any(|t| t.trim() == tag || t.trim() == "*"))
+            .unwrap_or(false);
+
+        println!("{} {} -> {} ({}){}", request.method(), request.url(),
+                 served.strip_prefix(&self.root).unwrap_or(&served).display(),
+                 encoding,
+                 if not_modified { " 304" } else { "" });
+
+        let mut headers = vec![
+            header("Content-Type", content_type(&path)),
+            header("Vary", "Accept-Encoding"),
+            header("ETag", &tag),
+        ];
+        if encoding != "identity" {
+            headers.push(header("Content-Encoding", encoding));
+        }
+
+        let (status, body) = match not_modified {
+            true => (304, Vec::new()),
+            false => (200, data),
+        };
+        let length = body.len();
+        request.respond(Response::new(StatusCode(status), headers, std::io::Cursor::new(body), Some(length), None))?;
+
+        Ok(())
+    }
+
+    // The acceptable variant of `path` with the highest q-value, ties going to the earlier
+    // encoding, or `identity` to serve `path` itself. None if the client accepts neither.
+    fn choose_variant(&self, path: &Path, accepted: &[(String, f32)]) -> Option<(&'static str, PathBuf)> {
+        let mut chosen: Option<(&'static str, PathBuf, f32)> = None;
+        for &(encoding, ext) in self.encodings.iter() {
+            let q = quality_of(accepted, encoding);
+            if q <= 0.0 || chosen.as_ref().map(|c| c.2 >= q).unwrap_or(false) {
+                continue;
+            }
+            let mut variant = path.as_os_str().to_owned();
+            variant.push(".");
+            variant.push(ext);
+            let variant = PathBuf::from(variant);
+            if variant.is_file() {
+                chosen = Some((encoding, variant, q));
+            }
+        }
+
+        // Unless listed (or refused by `*;q=0`), identity is acceptable as a last resort, and it
+        // loses ties to the variants
+        let identity = match accepted.iter().any(|(c, _)| c == "identity" || c == "*") {
+            true => quality_of(accepted, "identity"),
+            false => f32::MIN_POSITIVE,
+        };
+        if identity > 0.0 && chosen.as_ref().map(|c| identity > c.2).unwrap_or(true) {
+            return Some(("identity", path.to_path_buf()));
+        }
+
+        chosen.map(|(encoding, variant, _)| (encoding, variant))
+    }
+
+    // Maps a request URL to an existing file under the root, refusing to leave it
+    fn resolve(&self, url: &str) -> Option<PathBuf> {
+        let url_path = url.split(['?', '#']).next().unwrap_or("");
+        let relative = percent_decode(url_path.trim_start_matches('/'))?;
+        if relative.components().any(|c| !matches!(c, Component::Normal(_))) {
+            return None;
+        }
+
+        let mut path = self.root.join(relative);
+        if path.is_dir() {
+            path.push("index.html");
+        }
+
+        match path.is_file() {
+            true => Some(path),
+            false => None,
+        }
+    }
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("Invalid header!")
+}
+
+// Returns the (lowercased) codings and their q-values, per RFC 9110 section 12.5.3
+fn parse_accept_encoding(value: &str) -> Vec<(String, f32)> {
+    value.split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let coding = parts.next()?.trim().to_lowercase();
+            if coding.is_empty() {
+                return None;
+            }
+            let q = parts
+                .filter_map(|p| {
+                    let mut kv = p.splitn(2, '=');
+                    match (kv.next()?.trim(), kv.next()) {
+                        ("q", Some(q)) | ("Q", Some(q)) => q.trim().parse::<f32>().ok(),
+                        _ => None,
+                    }
+                })
+                .next()
+                .unwrap_or(1.0);
+            Some((coding, q))
+        })
+        .collect()
+}
+
+fn quality_of(accepted: &[(String, f32)], encoding: &str) -> f32 {
+    let find = |coding: &str| accepted.iter().find(|(c, _)| c == coding).map(|&(_, q)| q);
+    find(encoding)
+        .or_else(|| find("*"))
+        .unwrap_or(0.0)
+}
+
+// Decoded paths may name files that aren't valid UTF-8
+fn percent_decode(s: &str) -> Option<PathBuf> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+            },
+            b => {
+                decoded.push(b);
+                i += 1;
+            },
+        }
+    }
+
+    bytes_to_path(decoded)
+}
+
+#[cfg(unix)]
+fn bytes_to_path(bytes: Vec<u8>) -> Option<PathBuf> {
+    use std::os::unix::ffi::OsStringExt;
+    Some(PathBuf::from(std::ffi::OsString::from_vec(bytes)))
+}
+
+#[cfg(not(unix))]
+fn bytes_to_path(bytes: Vec<u8>) -> Option<PathBuf> {
+    String::from_utf8(bytes).ok().map(PathBuf::from)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use super::*;
+
+    #[test]
+    fn parses_accept_encoding() {
+        let accepted = parse_accept_encoding("gzip, BR;q=0.5 , zstd; q=0, identity;Q=0.1,,");
+        assert_eq!(accepted, vec![
+            ("gzip".to_owned(), 1.0),
+            ("br".to_owned(), 0.5),
+            ("zstd".to_owned(), 0.0),
+            ("identity".to_owned(), 0.1),
+        ]);
+    }
+
+    #[test]
+    fn wildcard_applies_to_unlisted_codings() {
+        let accepted = parse_accept_encoding("gzip;q=0, *;q=0.3");
+        assert_eq!(quality_of(&accepted, "gzip"), 0.0);
+        assert_eq!(quality_of(&accepted, "br"), 0.3);
+        assert_eq!(quality_of(&[], "br"), 0.0);
+    }
+
+    #[test]
+    fn chooses_variant_by_q_value() {
+        let root = tempfile::tempdir().unwrap();
+        let path = root.path().join("app.js");
+        for name in ["app.js", "app.js.gz", "app.js.br"].iter() {
+            std::fs::write(root.path().join(name), b"").unwrap();
+        }
+        let server = Server::new(root.path());
+        let choose = |header: &str| server.choose_variant(&path, &parse_accept_encoding(header)).map(|c| c.0);
+
+        // Brotli is preferred on a tie
+        assert_eq!(choose("gzip, br"), Some("br"));
+        assert_eq!(choose("gzip, br;q=0.8"), Some("gzip"));
+        // There is no zstd variant to serve
+        assert_eq!(choose("zstd, gzip;q=0.1"), Some("gzip"));
+        assert_eq!(choose("br;q=0, gzip;q=0"), Some("identity"));
+        assert_eq!(choose(""), Some("identity"));
+    }
+
+    #[test]
+    fn ranks_identity_with_the_variants() {
+        let root = tempfile::tempdir().unwrap();
+        let path = root.path().join("app.js");
+        for name in ["app.js", "app.js.gz"].iter() {
+            std::fs::write(root.path().join(name), b"").unwrap();
+        }
+        let server = Server::new(root.path());
+        let choose = |header: &str| server.choose_variant(&path, &parse_accept_encoding(header)).map(|c| c.0);
+
+        assert_eq!(choose("identity, gzip;q=0.5"), Some("identity"));
+        assert_eq!(choose("identity;q=0.5, gzip"), Some("gzip"));
+        // Compressed variants win ties
+        assert_eq!(choose("identity, gzip"), Some("gzip"));
+        assert_eq!(choose("*;q=0.5, gzip;q=0.4"), Some("identity"));
+        // Nothing acceptable exists, as there is no brotli variant
+        assert_eq!(choose("br, *;q=0"), None);
+        assert_eq!(choose("gzip;q=0, identity;q=0"), None);
+        assert_eq!(choose("*;q=0, identity"), Some("identity"));
+        assert_eq!(choose("*;q=0, gzip"), Some("gzip"));
+    }
+
+    #[test]
+    fn resolves_within_the_root() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("docs")).unwrap();
+        std::fs::write(root.path().join("docs/index.html"), b"").unwrap();
+        std::fs::write(root.path().join("a b.txt"), b"").unwrap();
+        let server = Server::new(root.path());
+
+        assert_eq!(server.resolve("/docs/"), Some(root.path().join("docs/index.html")));
+        assert_eq!(server.resolve("/a%20b.txt?v=1"), Some(root.path().join("a b.txt")));
+        assert_eq!(server.resolve("/../etc/passwd"), None);
+        assert_eq!(server.resolve("/missing"), None);
+    }
+}
@@ -1,23 +1,124 @@
 use ::*;
+use digest::SriAlgorithm;
 use errors::*;
 use separator::Separatable;
 use size::Size;
-use std::path::Path;
+use serde::Serializer;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 pub struct Parameters {
     pub compressor: CompressionAlgorithm,
     pub extension: String,
     pub quality: Option<u8>,
     pub threads: usize,
+    // Files at least this large are split across idle workers (gzip and zstd only)
+    pub parallel_threshold: Option<u64>,
+    pub case_sensitive: bool,
+    pub traversal: Traversal,
+    // Print progress and per-file errors to the console, for command-line use
     pub show_progress: bool,
-    pub show_summary: bool,
+    // Stat every matching file before dispatching them, most expensive first
+    pub largest_first: bool,
+    pub best_of: Option<BestOf>,
+    // Keep a FileRecord for every matching file in the returned statistics
+    pub record_files: bool,
+    // Include the SHA-256 of newly compressed outputs in their FileRecord
+    pub hash_outputs: bool,
+    // SRI digests to compute for each newly compressed source
+    pub integrity: Vec<SriAlgorithm>,
+    // Derive a strong ETag for each output from its SHA-256
+    pub etags: bool,
+    // Compress identical sources once, linking or copying the output for the rest
+    pub dedupe: bool,
+    // Write the SRI digests and ETags to `<source>.sri` and `<output>.etag` sidecar files
+    pub sidecars: bool,
+    // Give outputs the source's permissions, ownership (where permitted) and `xattrs`
+    pub preserve_attributes: bool,
+    // Extended attributes to copy, by name or namespace prefix (e.g. `user.*`)
+    pub xattrs: Vec<String>,
+    // Extensions to skip in addition to the built-in list of compressed formats
+    pub skip_exts: Vec<String>,
+    // Extensions to compress even though they are on the built-in list
+    pub compress_exts: Vec<String>,
+    // Files written by the run itself (the manifest and report), which are never compressed
+    pub exclude_paths: Vec<PathBuf>,
+    pub fail_fast: bool,
 }
 
+impl Parameters {
+    /// Default parameters for the given compressor, with console output disabled.
+    pub fn new(compressor: CompressionAlgorithm) -> Parameters {
+        Parameters {
+            extension: compressor.extension().to_owned(),
+            compressor,
+            quality: None,
+            threads: std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+            parallel_threshold: Some(16 * 1024 * 1024),
+            case_sensitive: true,
+            traversal: Traversal::default(),
+            show_progress: false,
+            largest_first: false,
+            best_of: None,
+            record_files: false,
+            hash_outputs: false,
+            integrity: Vec::new(),
+            etags: false,
+            dedupe: false,
+            sidecars: false,
+            preserve_attributes: true,
+            xattrs: vec!["security.selinux".to_owned()],
+            skip_exts: Vec::new(),
+            compress_exts: Vec::new(),
+            exclude_paths: Vec::new(),
+            fail_fast: false,
+        }
+    }
+}
+
+/// Controls which files and directories are visited while searching for matches.
+pub struct Traversal {
+    // Include dotfiles and dot-directories, other than version control metadata
+    pub hidden: bool,
+    // Hidden directories (by name) to descend into even without `hidden`
+    pub hidden_dirs: Vec<OsString>,
+    // Skip anything excluded by .gitignore, .ignore or .staticcompressignore files
+    pub ignore_files: bool,
+    // Descend into symlinked directories and compress symlinked files like any other
+    pub follow_symlinks: bool,
+    // Make the output for a symlinked source a symlink to the output for its target, rather
+    // than a compressed copy
+    pub link_symlinks: bool,
+    // Don't enter directories more than this many levels below the search root
+    pub max_depth: Option<usize>,
+    // Don't enter directories on a different filesystem than the search root
+    pub one_file_system: bool,
+}
+
+impl Default for Traversal {
+    fn default() -> Self {
+        Traversal {
+            hidden: false,
+            hidden_dirs: Vec::new(),
+            ignore_files: false,
+            follow_symlinks: true,
+            link_symlinks: false,
+            max_depth: None,
+            one_file_system: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
 pub enum CompressionAlgorithm {
     Brotli,
     GZip,
     WebP,
     Zopfli,
+    Zstd,
 }
 
 impl std::str::FromStr for CompressionAlgorithm {
@@ -28,21 +129,150 @@ impl std::str::FromStr for CompressionAlgorithm {
             "br" | "brotli" => CompressionAlgorithm::Brotli,
             "webp" => CompressionAlgorithm::WebP,
             "zopfli" => CompressionAlgorithm::Zopfli,
+            "zst" | "zstd" => CompressionAlgorithm::Zstd,
             _ => bail!("Unsupported compression algorithm option set!"),
         };
 
-        return Ok(r);
+        Ok(r)
+    }
+}
+
+impl CompressionAlgorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CompressionAlgorithm::Brotli => "brotli",
+            CompressionAlgorithm::GZip => "gzip",
+            CompressionAlgorithm::WebP => "webp",
+            CompressionAlgorithm::Zopfli => "zopfli",
+            CompressionAlgorithm::Zstd => "zstd",
+        }
+    }
+}
+
+impl std::fmt::Display for CompressionAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name())
     }
 }
 
 pub trait FileCompressor {
-    fn compress(&self, source: &Path, destination: &Path, quality: Option<u8>) -> Result<()>;
+    /// Compresses everything read from `source` into `destination`.
+    fn compress_stream(&self, source: &mut dyn Read, destination: &mut dyn Write, quality: Option<u8>) -> Result<()>;
+
+    /// Compresses the file at `source` into a newly-created file at `destination`.
+    fn compress(&self, source: &Path, destination: &Path, quality: Option<u8>) -> Result<()> {
+        compression::compress_file(self, source, destination, quality)
+    }
+
+    /// Compresses an in-memory buffer without touching the filesystem.
+    fn compress_buffer(&self, data: &[u8], quality: Option<u8>) -> Result<Vec<u8>> {
+        let mut src = data;
+        let mut dst = Vec::new();
+        self.compress_stream(&mut src, &mut dst, quality)?;
+        Ok(dst)
+    }
 }
 
 pub trait CompressionFormat {
     fn extension(&self) -> &'static str;
+    // The HTTP Content-Encoding token, if this is a transparent content encoding
+    fn content_encoding(&self) -> Option<&'static str>;
+}
+
+pub type Encoder = fn(&mut dyn Read, &mut dyn Write, Option<u8>) -> Result<()>;
+
+// A fixed encoder configuration competing against its peers in best-of mode
+pub struct Candidate {
+    pub name: &'static str,
+    // Overrides the user-supplied quality, if set
+    pub quality: Option<u8>,
+    pub encoder: Encoder,
+}
+
+// A set of candidates producing the same format; the smallest output wins
+pub struct BestOf {
+    pub candidates: Vec<Candidate>,
+}
+
+/// The outcome of a `compress_tree()` run.
+pub struct Report {
+    pub statistics: Statistics,
+    // Set if the run was stopped early because of `fail_fast`
+    pub aborted: bool,
+}
+
+#[derive(Clone, Copy)]
+pub enum ReportFormat {
+    Csv,
+    Json,
+}
+
+impl std::str::FromStr for ReportFormat {
+    type Err = errors::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let r = match s {
+            "csv" => ReportFormat::Csv,
+            "json" => ReportFormat::Json,
+            _ => bail!("Unsupported report format!"),
+        };
+
+        Ok(r)
+    }
 }
 
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FileStatus {
+    Compressed,
+    // Linked or copied from the output of an identical source
+    Deduplicated,
+    Skipped,
+    // Given a symlink to the output of the source's own symlink target
+    Symlinked,
+    Failed,
+    UpToDate,
+}
+
+#[derive(Serialize)]
+pub struct FileRecord {
+    #[serde(serialize_with = "serialize_path")]
+    pub source: PathBuf,
+    #[serde(serialize_with = "serialize_path")]
+    pub output: PathBuf,
+    pub algorithm: &'static str,
+    pub input_size: u64,
+    pub output_size: u64,
+    #[serde(serialize_with = "serialize_duration")]
+    pub duration: Duration,
+    pub status: FileStatus,
+    // Seconds since the epoch, shared by the source and its output
+    #[serde(skip)]
+    pub modified: u64,
+    // Hex-encoded SHA-256 of the output, if it was computed
+    #[serde(skip)]
+    pub sha256: Option<String>,
+    // The source's SRI digests, if they were computed
+    #[serde(skip)]
+    pub integrity: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct FileError {
+    #[serde(serialize_with = "serialize_path")]
+    pub path: PathBuf,
+    pub error: String,
+}
+
+fn serialize_path<S: Serializer>(path: &Path, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_str(&path.to_string_lossy())
+}
+
+// In (fractional) seconds
+fn serialize_duration<S: Serializer>(duration: &Duration, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+#[derive(Serialize)]
 pub struct Statistics {
     total_compressed: u64,
     total_compressed_now: u64,
@@ -50,6 +280,23 @@ pub struct Statistics {
     total_file_count_now: u32,
     total_uncompressed: u64,
     total_uncompressed_now: u64,
+    wins: BTreeMap<&'static str, u32>,
+    total_deduplicated: u32,
+    // Deduplicated outputs that had to be copied rather than hardlinked
+    total_dedupe_copies: u32,
+    // By hardlinked outputs
+    dedupe_bytes_saved: u64,
+    #[serde(serialize_with = "serialize_duration")]
+    dedupe_time_saved: Duration,
+    // Given a symlink to another output with --symlink-outputs, and so not in the totals above
+    total_symlinked: u32,
+    // Matching files not compressed because of their extension, other than existing outputs
+    total_skipped: u32,
+    errors: Vec<FileError>,
+    // Problems that didn't stop any file from being compressed
+    warnings: Vec<String>,
+    #[serde(skip)]
+    records: Vec<FileRecord>,
 }
 
 impl Statistics {
@@ -61,6 +308,16 @@ impl Statistics {
             total_file_count_now: 0,
             total_uncompressed: 0,
             total_uncompressed_now: 0,
+            wins: BTreeMap::new(),
+            total_deduplicated: 0,
+            total_dedupe_copies: 0,
+            dedupe_bytes_saved: 0,
+            dedupe_time_saved: Duration::from_secs(0),
+            total_symlinked: 0,
+            total_skipped: 0,
+            errors: Vec::new(),
+            warnings: Vec::new(),
+            records: Vec::new(),
         }
     }
 
@@ -76,27 +333,148 @@ impl Statistics {
         self.total_uncompressed += uncompressed_size;
     }
 
-    pub fn merge(&mut self, other: &Statistics) {
+    pub fn record_win(&mut self, candidate: &'static str) {
+        *self.wins.entry(candidate).or_insert(0) += 1;
+    }
+
+    pub fn record_dedupe(&mut self, hardlinked: bool, output_size: u64, time_saved: Duration) {
+        self.total_deduplicated += 1;
+        match hardlinked {
+            true => self.dedupe_bytes_saved += output_size,
+            false => self.total_dedupe_copies += 1,
+        }
+        self.dedupe_time_saved += time_saved;
+    }
+
+    pub fn record_symlink(&mut self) {
+        self.total_symlinked += 1;
+    }
+
+    pub fn record_skip(&mut self) {
+        self.total_skipped += 1;
+    }
+
+    pub fn record_file(&mut self, record: FileRecord) {
+        self.records.push(record);
+    }
+
+    pub fn records(&self) -> &[FileRecord] {
+        &self.records
+    }
+
+    pub fn record_error(&mut self, path: PathBuf, error: &Error) {
+        self.errors.push(FileError {
+            path,
+            error: error.to_string(),
+        });
+    }
+
+    pub fn errors(&self) -> &[FileError] {
+        &self.errors
+    }
+
+    pub fn record_warning(&mut self, warning: String) {
+        self.warnings.push(warning);
+    }
+
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    pub fn merge(&mut self, other: Statistics) {
         self.total_compressed += other.total_compressed;
         self.total_compressed_now += other.total_compressed_now;
         self.total_file_count += other.total_file_count;
         self.total_file_count_now += other.total_file_count_now;
         self.total_uncompressed += other.total_uncompressed;
         self.total_uncompressed_now += other.total_uncompressed_now;
+        for (candidate, count) in other.wins.iter() {
+            *self.wins.entry(candidate).or_insert(0) += count;
+        }
+        self.total_deduplicated += other.total_deduplicated;
+        self.total_dedupe_copies += other.total_dedupe_copies;
+        self.total_symlinked += other.total_symlinked;
+        self.total_skipped += other.total_skipped;
+        self.dedupe_bytes_saved += other.dedupe_bytes_saved;
+        self.dedupe_time_saved += other.dedupe_time_saved;
+        self.errors.extend(other.errors);
+        self.warnings.extend(other.warnings);
+        self.records.extend(other.records);
+    }
+
+    // Files compressed (or deduplicated) in this run
+    pub fn file_count_now(&self) -> u32 {
+        self.total_file_count_now
+    }
+
+    // Including those already up-to-date
+    pub fn file_count(&self) -> u32 {
+        self.total_file_count
+    }
+
+    pub fn compressed_size_now(&self) -> u64 {
+        self.total_compressed_now
+    }
+
+    pub fn compressed_size(&self) -> u64 {
+        self.total_compressed
+    }
+
+    pub fn uncompressed_size_now(&self) -> u64 {
+        self.total_uncompressed_now
+    }
+
+    pub fn uncompressed_size(&self) -> u64 {
+        self.total_uncompressed
+    }
+
+    // Best-of candidates by name, with the number of outputs each produced
+    pub fn wins(&self) -> &BTreeMap<&'static str, u32> {
+        &self.wins
+    }
+
+    pub fn deduplicated(&self) -> u32 {
+        self.total_deduplicated
+    }
+
+    pub fn dedupe_copies(&self) -> u32 {
+        self.total_dedupe_copies
+    }
+
+    pub fn dedupe_bytes_saved(&self) -> u64 {
+        self.dedupe_bytes_saved
+    }
+
+    pub fn dedupe_time_saved(&self) -> Duration {
+        self.dedupe_time_saved
+    }
+
+    pub fn symlinked(&self) -> u32 {
+        self.total_symlinked
+    }
+
+    pub fn skipped(&self) -> u32 {
+        self.total_skipped
     }
 
     pub fn savings_ratio(&self) -> f32 {
-        return self.total_compressed as f32 / self.total_uncompressed as f32;
+        self.total_compressed as f32 / self.total_uncompressed as f32
     }
 
     pub fn savings_ratio_now(&self) -> f32 {
-        return self.total_compressed_now as f32 / self.total_uncompressed_now as f32;
+        self.total_compressed_now as f32 / self.total_uncompressed_now as f32
+    }
+}
+
+impl Default for Statistics {
+    fn default() -> Self {
+        Statistics::new()
     }
 }
 
 impl std::fmt::Display for Statistics {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        writeln!(f, "")?;
+        writeln!(f)?;
         let table = table!(["", "This Run", "Total"],
                            ["Count", self.total_file_count_now.separated_string(), self.total_file_count.separated_string()],
                            ["Compressed Size", Size::from_bytes(self.total_compressed_now), Size::from_bytes(self.total_compressed)],
@@ -104,6 +482,29 @@ impl std::fmt::Display for Statistics {
                            ["Total Savings", format!("{:.2}%", 100f32 - 100f32 * self.savings_ratio_now()), format!("{:.2}%", 100f32 - 100f32 * self.savings_ratio())]);
 
         writeln!(f, "{}", table)?;
+
+        if !self.wins.is_empty() {
+            let mut table = table!(["Best-Of Candidate", "Wins"]);
+            for (candidate, count) in self.wins.iter() {
+                table.add_row(row![candidate, count.separated_string()]);
+            }
+            writeln!(f, "{}", table)?;
+        }
+
+        if self.total_deduplicated > 0 {
+            let hardlinked = self.total_deduplicated - self.total_dedupe_copies;
+            let table = table!(["Deduplicated Files", "Hardlinked", "Copied", "Disk Space Saved", "Compression Time Saved"],
+                               [self.total_deduplicated.separated_string(), hardlinked.separated_string(),
+                                self.total_dedupe_copies.separated_string(), Size::from_bytes(self.dedupe_bytes_saved),
+                                format!("{:.2}s", self.dedupe_time_saved.as_secs_f64())]);
+            writeln!(f, "{}", table)?;
+        }
+
+        if self.total_symlinked > 0 {
+            let table = table!(["Symlinked Files"], [self.total_symlinked.separated_string()]);
+            writeln!(f, "{}", table)?;
+        }
+
         Ok(())
     }
 }
@@ -0,0 +1,434 @@
+// A parallel directory walk. Each thread reads directories from its own queue, stealing from
+// the others once it runs dry, so that a slow read_dir() (e.g. over NFS) doesn't hold up the
+// rest of the search.
+
+extern crate crossbeam_deque;
+
+use self::crossbeam_deque::{Injector, Stealer, Worker};
+use errors::*;
+use ignores::IgnoreStack;
+use lists::{TEMP_SUFFIX, VCS_DIRS};
+use matcher::Matcher;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use structs::*;
+
+// Limits that apply to everything beneath one search root
+struct Root {
+    // Directories this many levels below the root aren't entered
+    max_depth: Option<usize>,
+    // The device of the root, with --one-file-system
+    device: Option<u64>,
+}
+
+// A directory waiting to be read
+struct Dir {
+    path: PathBuf,
+    depth: usize,
+    root: Arc<Root>,
+    ignores: Option<IgnoreStack>,
+}
+
+struct Walk<'a, F: 'a> {
+    matcher: &'a Matcher,
+    traversal: &'a Traversal,
+    callback: &'a F,
+    injector: Injector<Dir>,
+    // Directories queued or being read; the walk is over once this drops to zero
+    pending: AtomicUsize,
+    // Threads with nothing to do wait on `wake` until more directories are queued or the walk
+    // is over
+    idle: Mutex<()>,
+    wake: Condvar,
+    // Every directory entered so far, so that symlink loops (or several links to the same
+    // directory) don't search it more than once
+    visited: Mutex<HashSet<DirId>>,
+    // The first error, which stops every thread
+    failed: AtomicBool,
+    error: Mutex<Option<Error>>,
+    // Problems that didn't stop the walk, such as invalid lines in ignore files
+    warnings: Mutex<Vec<String>>,
+}
+
+/// Calls `callback` (from up to `threads` threads at once) for each matching file beneath
+/// `roots`, each of which may limit how deep it is searched. Returns the warnings raised
+/// along the way.
+pub fn walk<F>(roots: HashMap<PathBuf, Option<usize>>, matcher: &Matcher, traversal: &Traversal, threads: usize,
+               callback: &F) -> Result<Vec<String>>
+    where F: Fn(PathBuf) -> Result<()> + Sync
+{
+    let walk = Walk {
+        matcher,
+        traversal,
+        callback,
+        injector: Injector::new(),
+        pending: AtomicUsize::new(0),
+        idle: Mutex::new(()),
+        wake: Condvar::new(),
+        visited: Mutex::new(HashSet::new()),
+        failed: AtomicBool::new(false),
+        error: Mutex::new(None),
+        warnings: Mutex::new(Vec::new()),
+    };
+
+    for (path, max_depth) in roots {
+        let root = Arc::new(Root {
+            max_depth: match (max_depth, traversal.max_depth) {
+                (Some(a), Some(b)) => Some(std::cmp::min(a, b)),
+                (a, b) => a.or(b),
+            },
+            device: match traversal.one_file_system {
+                true => device(&path),
+                false => None,
+            },
+        });
+        // The search root itself is never ignored, so that e.g. a git-ignored build output
+        // directory can still be compressed by naming it explicitly
+        let ignores = match traversal.ignore_files {
+            true => Some(IgnoreStack::new(&path, &mut walk.warnings.lock().unwrap())?),
+            false => None,
+        };
+        if let Some(dir) = walk.visit(path, 0, &root, &ignores)? {
+            walk.pending.fetch_add(1, Ordering::SeqCst);
+            walk.injector.push(dir);
+        }
+    }
+
+    let workers: Vec<Worker<Dir>> = (0..std::cmp::max(1, threads)).map(|_| Worker::new_lifo()).collect();
+    let stealers: Vec<Stealer<Dir>> = workers.iter().map(|w| w.stealer()).collect();
+    std::thread::scope(|scope| {
+        for local in workers {
+            let walk = &walk;
+            let stealers = &stealers;
+            scope.spawn(move || walk.run(local, stealers));
+        }
+    });
+
+    match walk.error.into_inner().expect("Walker thread panicked") {
+        Some(e) => Err(e),
+        None => Ok(walk.warnings.into_inner().unwrap()),
+    }
+}
+
+impl<'a, F> Walk<'a, F>
+    where F: Fn(PathBuf) -> Result<()> + Sync
+{
+    fn run(&self, local: Worker<Dir>, stealers: &[Stealer<Dir>]) {
+        while !self.failed.load(Ordering::SeqCst) {
+            match local.pop().or_else(|| self.steal(&local, stealers)) {
+                Some(dir) => {
+                    if let Err(e) = self.read(dir, &local) {
+                        self.fail(e);
+                    }
+                    if self.pending.fetch_sub(1, Ordering::SeqCst) == 1 {
+                        self.notify(true);
+                    }
+                },
+                None => {
+                    let idle = self.idle.lock().unwrap();
+                    if self.pending.load(Ordering::SeqCst) == 0 {
+                        break;
+                    }
+                    // Another thread is still reading a directory that may yield more work. The
+                    // queues are checked again under the lock, so a notification can't be missed.
+                    let queued = !self.injector.is_empty() || stealers.iter().any(|s| !s.is_empty());
+                    if !queued && !self.failed.load(Ordering::SeqCst) {
+                        drop(self.wake.wait(idle).unwrap());
+                    }
+                },
+            }
+        }
+    }
+
+    // Wakes a waiting thread to take a newly queued directory, or all of them once there is
+    // nothing left to wait for
+    fn notify(&self, all: bool) {
+        let _idle = self.idle.lock().unwrap();
+        match all {
+            true => self.wake.notify_all(),
+            false => self.wake.notify_one(),
+        }
+    }
+
+    fn steal(&self, local: &Worker<Dir>, stealers: &[Stealer<Dir>]) -> Option<Dir> {
+        std::iter::repeat_with(|| {
+            self.injector.steal_batch_and_pop(local)
+                .or_else(|| stealers.iter().map(|s| s.steal()).collect())
+        })
+        .find(|s| !s.is_retry())
+        .and_then(|s| s.success())
+    }
+
+    fn fail(&self, error: Error) {
+        let mut first = self.error.lock().unwrap();
+        if first.is_none() {
+            *first = Some(error);
+        }
+        self.failed.store(true, Ordering::SeqCst);
+        self.notify(true);
+    }
+
+    fn read(&self, dir: Dir, local: &Worker<Dir>) -> Result<()> {
+        let ignores = match dir.ignores {
+            Some(ref ignores) => {
+                let mut warnings = Vec::new();
+                let entered = ignores.enter(&dir.path, &mut warnings)?;
+                self.warnings.lock().unwrap().extend(warnings);
+                Some(entered)
+            },
+            None => None,
+        };
+
+        for child in dir.path.read_dir()? {
+            if self.failed.load(Ordering::SeqCst) {
+                break;
+            }
+
+            let child_path = child?.path();
+            if let Some(ref ignores) = ignores {
+                if ignores.is_ignored(&child_path, child_path.is_dir()) {
+                    continue;
+                }
+            }
+            if let Some(subdir) = self.visit(child_path, dir.depth + 1, &dir.root, &ignores)? {
+                self.pending.fetch_add(1, Ordering::SeqCst);
+                local.push(subdir);
+                self.notify(false);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Passes `path` to the callback if it is a matching file, or returns it if it is a
+    // directory that should be searched
+    fn visit(&self, path: PathBuf, depth: usize, root: &Arc<Root>, ignores: &Option<IgnoreStack>) -> Result<Option<Dir>> {
+        let is_dir = path.is_dir();
+        if ::is_hidden(&path) && !hidden_allowed(&path, is_dir, self.traversal) {
+            return Ok(None);
+        }
+
+        // A symlink given as the search root is always followed
+        if depth > 0 && path.is_symlink() {
+            let follow = match is_dir {
+                true => self.traversal.follow_symlinks,
+                false => self.traversal.follow_symlinks || self.traversal.link_symlinks,
+            };
+            if !follow {
+                return Ok(None);
+            }
+        }
+
+        if !is_dir {
+            if self.matcher.is_match(&path) {
+                (self.callback)(path)?;
+            }
+            return Ok(None);
+        }
+
+        if root.max_depth.map(|max| depth >= max).unwrap_or(false) {
+            return Ok(None);
+        }
+        if depth > 0 && !self.matcher.may_match_within(&path) {
+            return Ok(None);
+        }
+        if root.device.is_some() && depth > 0 && device(&path) != root.device {
+            // A mount point
+            return Ok(None);
+        }
+        if let Some(id) = dir_id(&path) {
+            if !self.visited.lock().unwrap().insert(id) {
+                return Ok(None);
+            }
+        }
+
+        Ok(Some(Dir {
+            path,
+            depth,
+            root: root.clone(),
+            ignores: ignores.clone(),
+        }))
+    }
+}
+
+// Whether a hidden file or directory should be searched anyway
+fn hidden_allowed(path: &Path, is_dir: bool, traversal: &Traversal) -> bool {
+    let name = match path.file_name() {
+        Some(name) => name,
+        None => return true,
+    };
+
+    if is_dir {
+        if traversal.hidden_dirs.iter().any(|d| d == name) {
+            return true;
+        }
+        traversal.hidden && ::str_search(VCS_DIRS, &name.to_string_lossy(), true).is_err()
+    }
+    else {
+        traversal.hidden && !name.to_string_lossy().ends_with(TEMP_SUFFIX)
+    }
+}
+
+// Identifies a directory regardless of the path it was reached by
+#[cfg(unix)]
+type DirId = (u64, u64);
+
+#[cfg(unix)]
+fn dir_id(path: &Path) -> Option<DirId> {
+    use std::os::unix::fs::MetadataExt;
+    std::fs::metadata(path).ok().map(|m| (m.dev(), m.ino()))
+}
+
+#[cfg(unix)]
+fn device(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    std::fs::metadata(path).ok().map(|m| m.dev())
+}
+
+#[cfg(not(unix))]
+type DirId = PathBuf;
+
+#[cfg(not(unix))]
+fn dir_id(path: &Path) -> Option<DirId> {
+    std::fs::canonicalize(path).ok()
+}
+
+// Not available, so --one-file-system has no effect
+#[cfg(not(unix))]
+fn device(_path: &Path) -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use super::*;
+
+    struct Tree {
+        // Not hidden, unlike the default `.tmp` prefix
+        dir: tempfile::TempDir,
+    }
+
+    impl Tree {
+        fn new(files: &[&str]) -> Tree {
+            let dir = tempfile::Builder::new().prefix("walk").tempdir().unwrap();
+            for file in files {
+                let path = dir.path().join(file);
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(path, b"").unwrap();
+            }
+            Tree { dir }
+        }
+
+        fn root(&self) -> &Path {
+            self.dir.path()
+        }
+
+        // The matching files, relative to the root
+        fn walk(&self, filter: &str, depth: Option<usize>, traversal: &Traversal) -> Result<Vec<String>> {
+            self.search(filter, depth, traversal).map(|(found, _)| found)
+        }
+
+        fn warnings(&self, filter: &str, depth: Option<usize>, traversal: &Traversal) -> Vec<String> {
+            self.search(filter, depth, traversal).unwrap().1
+        }
+
+        fn search(&self, filter: &str, depth: Option<usize>, traversal: &Traversal) -> Result<(Vec<String>, Vec<String>)> {
+            let filters = vec![format!("{}/{}", self.root().display(), filter)];
+            let matcher = Matcher::new(&filters, true)?;
+            let mut roots = HashMap::new();
+            roots.insert(self.root().to_path_buf(), depth);
+
+            let found = Mutex::new(Vec::new());
+            let warnings = walk(roots, &matcher, traversal, 3, &|path: PathBuf| {
+                let relative = path.strip_prefix(self.root()).unwrap().to_string_lossy().into_owned();
+                found.lock().unwrap().push(relative);
+                Ok(())
+            })?;
+
+            let mut found = found.into_inner().unwrap();
+            found.sort();
+            Ok((found, warnings))
+        }
+    }
+
+    // Entering a directory with an invalid ignore file raises a warning
+    fn poison(dir: &Path) {
+        std::fs::create_dir_all(dir).unwrap();
+        std::fs::write(dir.join(".ignore"), "{a\n").unwrap();
+    }
+
+    fn with_ignore_files() -> Traversal {
+        Traversal { ignore_files: true, ..Traversal::default() }
+    }
+
+    #[test]
+    fn finds_matching_files() {
+        let tree = Tree::new(&["a.js", "b.css", "x/c.js", "x/y/d.js", ".hidden.js", ".git/e.js"]);
+        assert_eq!(tree.walk("**/*.js", None, &Traversal::default()).unwrap(), vec!["a.js", "x/c.js", "x/y/d.js"]);
+        assert_eq!(tree.walk("*/*.js", None, &Traversal::default()).unwrap(), vec!["x/c.js"]);
+
+        let hidden = Traversal { hidden: true, ..Traversal::default() };
+        assert_eq!(tree.walk("**/*.js", None, &hidden).unwrap(), vec![".hidden.js", "a.js", "x/c.js", "x/y/d.js"]);
+    }
+
+    #[test]
+    fn shares_a_wide_tree_between_threads() {
+        let files: Vec<String> = (0..50).flat_map(|i| vec![format!("{}/a.js", i), format!("{}/x/b.js", i)]).collect();
+        let files: Vec<&str> = files.iter().map(String::as_str).collect();
+        let tree = Tree::new(&files);
+
+        let mut expected = files.clone();
+        expected.sort();
+        for _ in 0..20 {
+            assert_eq!(tree.walk("**/*.js", None, &Traversal::default()).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn never_enters_directories_that_cannot_match() {
+        let tree = Tree::new(&["public/a.js", "private/b.js"]);
+        poison(&tree.root().join("private"));
+        assert_eq!(tree.walk("public/*.js", None, &with_ignore_files()).unwrap(), vec!["public/a.js"]);
+        assert!(tree.warnings("public/*.js", None, &with_ignore_files()).is_empty());
+        // The poisoned directory is entered once it could contain a match, and doesn't stop the walk
+        assert_eq!(tree.walk("**/*.js", None, &with_ignore_files()).unwrap(), vec!["private/b.js", "public/a.js"]);
+        assert_eq!(tree.warnings("**/*.js", None, &with_ignore_files()).len(), 1);
+    }
+
+    #[test]
+    fn stops_at_the_maximum_depth() {
+        let tree = Tree::new(&["a.js", "x/b.js", "x/y/c.js"]);
+        poison(&tree.root().join("x/y"));
+        assert_eq!(tree.warnings("**/*.js", None, &with_ignore_files()).len(), 1);
+        // Depth derived from the filters
+        assert_eq!(tree.walk("**/*.js", Some(2), &with_ignore_files()).unwrap(), vec!["a.js", "x/b.js"]);
+        assert!(tree.warnings("**/*.js", Some(2), &with_ignore_files()).is_empty());
+
+        let traversal = Traversal { max_depth: Some(1), ..with_ignore_files() };
+        assert_eq!(tree.walk("**/*.js", None, &traversal).unwrap(), vec!["a.js"]);
+        assert!(tree.warnings("**/*.js", None, &traversal).is_empty());
+        // The smaller of the two applies
+        assert_eq!(tree.walk("**/*.js", Some(3), &traversal).unwrap(), vec!["a.js"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn follows_symlinks_once() {
+        let tree = Tree::new(&["x/a.js"]);
+        std::os::unix::fs::symlink("..", tree.root().join("x/loop")).unwrap();
+        std::os::unix::fs::symlink("x", tree.root().join("y")).unwrap();
+        std::os::unix::fs::symlink("x/a.js", tree.root().join("b.js")).unwrap();
+
+        // Each directory is searched once, by whichever path reaches it first
+        let found = tree.walk("**/*.js", None, &Traversal::default()).unwrap();
+        assert_eq!(found.len(), 2, "{:?}", found);
+        assert!(found.contains(&"b.js".to_owned()));
+
+        let unfollowed = Traversal { follow_symlinks: false, ..Traversal::default() };
+        assert_eq!(tree.walk("**/*.js", None, &unfollowed).unwrap(), vec!["x/a.js"]);
+    }
+}
@@ -0,0 +1,421 @@
+// Generates web server configuration snippets for serving the precompressed variants
+
+use errors::*;
+use lists::*;
+use std::fmt::Write;
+use structs::*;
+
+#[derive(Clone, Copy)]
+pub enum WebServer {
+    Apache,
+    Caddy,
+    Lighttpd,
+    Nginx,
+}
+
+impl std::str::FromStr for WebServer {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let r = match s {
+            "apache" | "httpd" => WebServer::Apache,
+            "caddy" => WebServer::Caddy,
+            "lighttpd" => WebServer::Lighttpd,
+            "nginx" => WebServer::Nginx,
+            _ => bail!("Unsupported web server!"),
+        };
+
+        Ok(r)
+    }
+}
+
+// A precompressed variant sitting alongside the original file
+pub struct Variant {
+    pub content_encoding: &'static str,
+    pub extension: String,
+}
+
+impl Variant {
+    pub fn new(compressor: &CompressionAlgorithm, extension: Option<&str>) -> Result<Variant> {
+        let content_encoding = compressor.content_encoding()
+            .ok_or_else(|| format!("{} output is not served via Content-Encoding", compressor))?;
+
+        Ok(Variant {
+            content_encoding,
+            extension: extension.unwrap_or(compressor.extension())
+                .trim_matches(|c: char| c.is_whitespace() || c.is_control() || c == '.')
+                .to_owned(),
+        })
+    }
+
+    // Whether the server's built-in precompression support will find this variant
+    fn is_standard(&self) -> bool {
+        match self.content_encoding {
+            "br" => self.extension == "br",
+            "gzip" => self.extension == "gz",
+            "zstd" => self.extension == "zst",
+            _ => false,
+        }
+    }
+}
+
+// `variants` are listed in order of preference
+pub fn generate_config(server: WebServer, variants: &[Variant], location: &str) -> String {
+    let mut config = String::new();
+    match server {
+        WebServer::Apache => apache_config(&mut config, variants),
+        WebServer::Caddy => caddy_config(&mut config, variants, location),
+        WebServer::Lighttpd => lighttpd_config(&mut config, variants, location),
+        WebServer::Nginx => nginx_config(&mut config, variants, location),
+    }
+    .expect("Writing to a String cannot fail");
+    config
+}
+
+fn describe(variants: &[Variant]) -> String {
+    let described: Vec<String> = variants.iter()
+        .map(|v| format!("{} (.{})", v.content_encoding, v.extension))
+        .collect();
+    described.join(", ")
+}
+
+// Regular expression-safe version of an extension
+fn regex_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if !c.is_alphanumeric() && c != '_' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn nginx_config(f: &mut String, variants: &[Variant], location: &str) -> std::fmt::Result {
+    writeln!(f, "# Generated by static-compress for {}", describe(variants))?;
+    let custom: Vec<&Variant> = variants.iter().filter(|v| !v.is_standard()).collect();
+
+    if !custom.is_empty() {
+        writeln!(f, "# Place this map in the http {{}} block. Only the most preferred encoding the client")?;
+        writeln!(f, "# accepts is tried before falling back to the uncompressed file.")?;
+        writeln!(f, "map $http_accept_encoding $static_compress_suffix {{")?;
+        writeln!(f, "    default \"\";")?;
+        for variant in custom.iter() {
+            writeln!(f, "    \"~*\\b{}\\b\" \".{}\";", variant.content_encoding, variant.extension)?;
+        }
+        writeln!(f, "}}")?;
+        writeln!(f)?;
+    }
+
+    writeln!(f, "location {} {{", location)?;
+    for variant in variants.iter().filter(|v| v.is_standard()) {
+        match variant.content_encoding {
+            "br" => writeln!(f, "    brotli_static on; # Requires ngx_brotli")?,
+            "gzip" => {
+                writeln!(f, "    gzip_static on;")?;
+                writeln!(f, "    gzip_vary on;")?;
+            },
+            "zstd" => writeln!(f, "    zstd_static on; # Requires zstd-nginx-module")?,
+            _ => {},
+        }
+    }
+    if !custom.is_empty() {
+        writeln!(f, "    if (-f $request_filename$static_compress_suffix) {{")?;
+        writeln!(f, "        rewrite ^ $uri$static_compress_suffix last;")?;
+        writeln!(f, "    }}")?;
+    }
+    writeln!(f, "}}")?;
+
+    // nginx derives the Content-Type from the served file's extension, which is wrong for
+    // variants it doesn't natively support, so it has to be set explicitly per original type.
+    for variant in custom {
+        let ext = regex_escape(&variant.extension);
+        let types = MIME_TYPES.iter()
+            .map(|&(original, mime)| (format!("\\.{}\\.{}$", regex_escape(original), ext), mime))
+            .chain(std::iter::once((format!("\\.{}$", ext), DEFAULT_MIME_TYPE)));
+        for (pattern, mime) in types {
+            writeln!(f)?;
+            writeln!(f, "location ~* {} {{", pattern)?;
+            writeln!(f, "    gzip off;")?;
+            writeln!(f, "    types {{ }}")?;
+            writeln!(f, "    default_type \"{}\";", mime)?;
+            writeln!(f, "    add_header Content-Encoding {};", variant.content_encoding)?;
+            writeln!(f, "    add_header Vary Accept-Encoding;")?;
+            writeln!(f, "}}")?;
+        }
+    }
+
+    Ok(())
+}
+
+fn apache_config(f: &mut String, variants: &[Variant]) -> std::fmt::Result {
+    writeln!(f, "# Generated by static-compress for {}", describe(variants))?;
+    writeln!(f, "# Requires mod_rewrite, mod_mime and mod_headers. Place in a <Directory> block or .htaccess.")?;
+    writeln!(f, "RewriteEngine On")?;
+    for variant in variants {
+        writeln!(f)?;
+        writeln!(f, "RewriteCond \"%{{HTTP:Accept-Encoding}}\" \"\\b{}\\b\"", variant.content_encoding)?;
+        writeln!(f, "RewriteCond \"%{{REQUEST_FILENAME}}\\.{}\" -s", variant.extension)?;
+        writeln!(f, "RewriteRule \"^(.+)$\" \"$1\\.{}\" [QSA,L]", variant.extension)?;
+        writeln!(f, "# Don't compress the precompressed file again")?;
+        writeln!(f, "RewriteRule \"\\.{}$\" \"-\" [E=no-gzip:1,E=no-brotli:1]", regex_escape(&variant.extension))?;
+    }
+
+    // mod_mime takes the Content-Type from the original extension (e.g. .css in foo.css.gz)
+    // and the Content-Encoding from the variant's, as long as the latter isn't also a type.
+    writeln!(f)?;
+    writeln!(f, "<IfModule mod_mime.c>")?;
+    for variant in variants {
+        writeln!(f, "    RemoveType .{}", variant.extension)?;
+        writeln!(f, "    AddEncoding {} .{}", variant.content_encoding, variant.extension)?;
+    }
+    writeln!(f, "</IfModule>")?;
+    writeln!(f)?;
+    writeln!(f, "<IfModule mod_headers.c>")?;
+    writeln!(f, "    Header merge Vary Accept-Encoding")?;
+    writeln!(f, "</IfModule>")?;
+
+    Ok(())
+}
+
+fn caddy_config(f: &mut String, variants: &[Variant], location: &str) -> std::fmt::Result {
+    let prefix = location.trim_end_matches('/');
+    writeln!(f, "# Generated by static-compress for {}", describe(variants))?;
+    writeln!(f, "# Place inside your site block.")?;
+
+    // `precompressed` only knows the standard extensions; the rest are matched explicitly,
+    // setting the Content-Type of the original file.
+    for variant in variants.iter().filter(|v| !v.is_standard()) {
+        let name = variant.extension.replace(|c: char| !c.is_alphanumeric(), "_");
+        let types = MIME_TYPES.iter()
+            .map(|&(original, mime)| (original, format!("{}/*.{}", prefix, original), mime))
+            .chain(std::iter::once(("other", format!("{}/*", prefix), DEFAULT_MIME_TYPE)));
+        for (original, path, mime) in types {
+            writeln!(f)?;
+            writeln!(f, "@static_compress_{}_{} {{", name, original)?;
+            writeln!(f, "    path {}", path)?;
+            writeln!(f, "    header Accept-Encoding *{}*", variant.content_encoding)?;
+            writeln!(f, "    file {{path}}.{}", variant.extension)?;
+            writeln!(f, "}}")?;
+            writeln!(f, "handle @static_compress_{}_{} {{", name, original)?;
+            writeln!(f, "    rewrite * {{path}}.{}", variant.extension)?;
+            writeln!(f, "    header Content-Type \"{}\"", mime)?;
+            writeln!(f, "    header Content-Encoding {}", variant.content_encoding)?;
+            writeln!(f, "    header Vary Accept-Encoding")?;
+            writeln!(f, "    file_server")?;
+            writeln!(f, "}}")?;
+        }
+    }
+
+    let standard: Vec<&str> = variants.iter()
+        .filter(|v| v.is_standard())
+        .map(|v| v.content_encoding)
+        .collect();
+    writeln!(f)?;
+    writeln!(f, "handle {}/* {{", prefix)?;
+    match standard.is_empty() {
+        true => writeln!(f, "    file_server")?,
+        false => {
+            writeln!(f, "    file_server {{")?;
+            writeln!(f, "        precompressed {}", standard.join(" "))?;
+            writeln!(f, "    }}")?;
+        },
+    }
+    writeln!(f, "}}")?;
+
+    Ok(())
+}
+
+fn lighttpd_config(f: &mut String, variants: &[Variant], location: &str) -> std::fmt::Result {
+    writeln!(f, "# Generated by static-compress for {}", describe(variants))?;
+    writeln!(f, "# lighttpd.conf; save the output of `static-compress config lighttpd --lua-script` (with the")?;
+    writeln!(f, "# same compressors and extensions) as /etc/lighttpd/static-compress.lua")?;
+    writeln!(f, "server.modules += ( \"mod_magnet\" )")?;
+    match location {
+        "/" => writeln!(f, "magnet.attract-physical-path-to = ( \"/etc/lighttpd/static-compress.lua\" )")?,
+        _ => {
+            writeln!(f, "$HTTP[\"url\"] =^ \"{}\" {{", location)?;
+            writeln!(f, "    magnet.attract-physical-path-to = ( \"/etc/lighttpd/static-compress.lua\" )")?;
+            writeln!(f, "}}")?;
+        },
+    }
+
+    Ok(())
+}
+
+/// The mod_magnet script referenced by the lighttpd configuration, which picks the variant to
+/// serve. `variants` are listed in order of preference.
+pub fn generate_lighttpd_script(variants: &[Variant]) -> String {
+    let mut script = String::new();
+    lighttpd_script(&mut script, variants).expect("Writing to a String cannot fail");
+    script
+}
+
+fn lighttpd_script(f: &mut String, variants: &[Variant]) -> std::fmt::Result {
+    writeln!(f, "-- Generated by static-compress for {}", describe(variants))?;
+    writeln!(f, "local encodings = {{")?;
+    for variant in variants {
+        writeln!(f, "    {{ token = \"{}\", suffix = \".{}\" }},", variant.content_encoding, variant.extension)?;
+    }
+    writeln!(f, "}}")?;
+    writeln!(f, "local types = {{")?;
+    for &(ext, mime) in MIME_TYPES {
+        writeln!(f, "    [\"{}\"] = \"{}\",", ext, mime)?;
+    }
+    writeln!(f, "}}")?;
+    writeln!(f, "local default_type = \"{}\"", DEFAULT_MIME_TYPE)?;
+    writeln!(f)?;
+    f.push_str(r#"-- The q-value of each coding in an Accept-Encoding header, per RFC 9110 section 12.5.3
+local function accepted(header)
+    local q = {}
+    for item in string.gmatch(header, "[^,]+") do
+        local coding = string.match(item, "^%s*([^;%s]+)")
+        if coding then
+            local value = string.match(item, ";%s*[qQ]%s*=%s*([0-9.]+)")
+            q[string.lower(coding)] = tonumber(value) or 1
+        end
+    end
+    return q
+end
+
+local q = accepted(lighty.request["Accept-Encoding"] or "")
+local path = lighty.env["physical.path"]
+lighty.header["Vary"] = "Accept-Encoding"
+
+-- The existing variant with the highest q-value, ties going to the earlier encoding
+local chosen, chosen_q = nil, 0
+for _, encoding in ipairs(encodings) do
+    local quality = q[encoding.token] or q["*"] or 0
+    if quality > chosen_q then
+        local st = lighty.stat(path .. encoding.suffix)
+        if st and st.is_file then
+            chosen, chosen_q = encoding, quality
+        end
+    end
+end
+
+if chosen then
+    -- The Content-Type must be that of the original file, not the variant
+    local ext = string.match(path, "%.([^./]+)$")
+    lighty.header["Content-Type"] = (ext and types[string.lower(ext)]) or default_type
+    lighty.header["Content-Encoding"] = chosen.token
+    lighty.env["physical.path"] = path .. chosen.suffix
+end
+return 0
+"#);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variants(specs: &[(CompressionAlgorithm, Option<&str>)]) -> Vec<Variant> {
+        specs.iter().map(|&(compressor, ext)| Variant::new(&compressor, ext).unwrap()).collect()
+    }
+
+    fn standard() -> Vec<Variant> {
+        variants(&[(CompressionAlgorithm::Brotli, None), (CompressionAlgorithm::GZip, None)])
+    }
+
+    #[test]
+    fn nginx() {
+        assert_eq!(generate_config(WebServer::Nginx, &standard(), "/"), r#"# Generated by static-compress for br (.br), gzip (.gz)
+location / {
+    brotli_static on; # Requires ngx_brotli
+    gzip_static on;
+    gzip_vary on;
+}
+"#);
+
+        let custom = generate_config(WebServer::Nginx, &variants(&[(CompressionAlgorithm::GZip, Some("gzip"))]), "/");
+        assert!(custom.contains("map $http_accept_encoding $static_compress_suffix {\n    default \"\";\n    \"~*\\bgzip\\b\" \".gzip\";\n}\n"));
+        assert!(custom.contains(r#"
+location ~* \.css\.gzip$ {
+    gzip off;
+    types { }
+    default_type "text/css; charset=utf-8";
+    add_header Content-Encoding gzip;
+    add_header Vary Accept-Encoding;
+}
+"#));
+        assert!(!custom.contains("gzip_static"));
+    }
+
+    #[test]
+    fn apache() {
+        assert_eq!(generate_config(WebServer::Apache, &variants(&[(CompressionAlgorithm::GZip, None)]), "/"), r#"# Generated by static-compress for gzip (.gz)
+# Requires mod_rewrite, mod_mime and mod_headers. Place in a <Directory> block or .htaccess.
+RewriteEngine On
+
+RewriteCond "%{HTTP:Accept-Encoding}" "\bgzip\b"
+RewriteCond "%{REQUEST_FILENAME}\.gz" -s
+RewriteRule "^(.+)$" "$1\.gz" [QSA,L]
+# Don't compress the precompressed file again
+RewriteRule "\.gz$" "-" [E=no-gzip:1,E=no-brotli:1]
+
+<IfModule mod_mime.c>
+    RemoveType .gz
+    AddEncoding gzip .gz
+</IfModule>
+
+<IfModule mod_headers.c>
+    Header merge Vary Accept-Encoding
+</IfModule>
+"#);
+    }
+
+    #[test]
+    fn caddy() {
+        assert_eq!(generate_config(WebServer::Caddy, &standard(), "/assets/"), r#"# Generated by static-compress for br (.br), gzip (.gz)
+# Place inside your site block.
+
+handle /assets/* {
+    file_server {
+        precompressed br gzip
+    }
+}
+"#);
+
+        let custom = generate_config(WebServer::Caddy, &variants(&[(CompressionAlgorithm::Zstd, Some("zstd"))]), "/");
+        assert!(custom.contains(r#"
+@static_compress_zstd_css {
+    path /*.css
+    header Accept-Encoding *zstd*
+    file {path}.zstd
+}
+handle @static_compress_zstd_css {
+    rewrite * {path}.zstd
+    header Content-Type "text/css; charset=utf-8"
+    header Content-Encoding zstd
+    header Vary Accept-Encoding
+    file_server
+}
+"#));
+        assert!(custom.ends_with("handle /* {\n    file_server\n}\n"));
+    }
+
+    #[test]
+    fn lighttpd() {
+        assert_eq!(generate_config(WebServer::Lighttpd, &standard(), "/static/"), r#"# Generated by static-compress for br (.br), gzip (.gz)
+# lighttpd.conf; save the output of `static-compress config lighttpd --lua-script` (with the
+# same compressors and extensions) as /etc/lighttpd/static-compress.lua
+server.modules += ( "mod_magnet" )
+$HTTP["url"] =^ "/static/" {
+    magnet.attract-physical-path-to = ( "/etc/lighttpd/static-compress.lua" )
+}
+"#);
+        assert!(generate_config(WebServer::Lighttpd, &standard(), "/")
+            .ends_with("\nmagnet.attract-physical-path-to = ( \"/etc/lighttpd/static-compress.lua\" )\n"));
+
+        // The script is printed on its own, so contains no configuration
+        let script = generate_lighttpd_script(&variants(&[(CompressionAlgorithm::Brotli, None), (CompressionAlgorithm::GZip, Some("gzip"))]));
+        assert!(script.starts_with("-- Generated by static-compress for br (.br), gzip (.gzip)\nlocal encodings = {\n    { token = \"br\", suffix = \".br\" },\n    { token = \"gzip\", suffix = \".gzip\" },\n}\n"));
+        assert!(script.contains("    [\"css\"] = \"text/css; charset=utf-8\",\n"));
+        assert!(!script.contains("server.modules"));
+        // Codings are matched by their whole token and q-value, not as substrings
+        assert!(!script.contains("string.find"));
+        assert!(script.contains("local quality = q[encoding.token] or q[\"*\"] or 0\n    if quality > chosen_q then"));
+        assert!(script.ends_with("end\nreturn 0\n"));
+    }
+}